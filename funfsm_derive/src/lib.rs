@@ -0,0 +1,164 @@
+//! `#[derive(FsmTypes)]` for funfsm. Use it through funfsm's `derive` feature rather than
+//! depending on this crate directly.
+//!
+//! ```text
+//! #[derive(FsmTypes)]
+//! #[fsm(context = Context, msg = BowlMsg, output = StoreReq, states(empty, full))]
+//! pub struct BowlTypes;
+//! ```
+//!
+//! This implements `FsmTypes` for `BowlTypes`. Listing the state functions in `states(...)` also
+//! generates a `BowlState` enum with one variant per state (the type name with any `Types` suffix
+//! replaced by `State`). The enum converts to and from state names and `StateFn`s, can be given to
+//! the constraint macros as a `StateName`, and builds a `StateRegistry` of every state with
+//! `BowlState::registry()`.
+
+extern crate proc_macro;
+extern crate proc_macro2;
+#[macro_use]
+extern crate quote;
+extern crate syn;
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use syn::{DeriveInput, Ident, Type};
+
+#[proc_macro_derive(FsmTypes, attributes(fsm))]
+pub fn derive_fsm_types(input: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(input as DeriveInput);
+    match expand(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into()
+    }
+}
+
+#[derive(Default)]
+struct Args {
+    context: Option<Type>,
+    msg: Option<Type>,
+    output: Option<Type>,
+    states: Vec<Ident>
+}
+
+fn parse_args(input: &DeriveInput) -> syn::Result<Args> {
+    let mut args = Args::default();
+    for attr in input.attrs.iter().filter(|a| a.path().is_ident("fsm")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("context") {
+                args.context = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("msg") {
+                args.msg = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("output") {
+                args.output = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("states") {
+                meta.parse_nested_meta(|state| {
+                    match state.path.get_ident() {
+                        Some(ident) => args.states.push(ident.clone()),
+                        None => return Err(state.error("expected the name of a state function"))
+                    }
+                    Ok(())
+                })?;
+            } else {
+                return Err(meta.error("expected `context`, `msg`, `output` or `states`"));
+            }
+            Ok(())
+        })?;
+    }
+    Ok(args)
+}
+
+fn expand(input: &DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let args = parse_args(input)?;
+    let name = &input.ident;
+    let missing = |what| syn::Error::new_spanned(name, format!("missing #[fsm({} = ...)]", what));
+    let context = args.context.as_ref().ok_or_else(|| missing("context"))?;
+    let msg = args.msg.as_ref().ok_or_else(|| missing("msg"))?;
+    let output = args.output.as_ref().ok_or_else(|| missing("output"))?;
+
+    let mut tokens = quote! {
+        impl ::funfsm::FsmTypes for #name {
+            type Context = #context;
+            type Msg = #msg;
+            type Output = #output;
+        }
+    };
+    if !args.states.is_empty() {
+        tokens.extend(expand_states(input, &args.states));
+    }
+    Ok(tokens)
+}
+
+fn expand_states(input: &DeriveInput, states: &[Ident]) -> proc_macro2::TokenStream {
+    let name = &input.ident;
+    let vis = &input.vis;
+    let type_name = name.to_string();
+    let base = type_name.strip_suffix("Types").unwrap_or(&type_name);
+    let enum_name = Ident::new(&format!("{}State", base), Span::call_site());
+    let variants: Vec<Ident> = states.iter()
+        .map(|s| Ident::new(&camel_case(&s.to_string()), s.span()))
+        .collect();
+    let names: Vec<String> = states.iter().map(|s| s.to_string()).collect();
+    let count = states.len();
+
+    quote! {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        #vis enum #enum_name {
+            #(#variants),*
+        }
+
+        impl #enum_name {
+            pub const ALL: [#enum_name; #count] = [#(#enum_name::#variants),*];
+
+            pub fn name(&self) -> &'static str {
+                match *self {
+                    #(#enum_name::#variants => #names),*
+                }
+            }
+
+            pub fn from_name(name: &str) -> Option<#enum_name> {
+                match name {
+                    #(#names => Some(#enum_name::#variants),)*
+                    _ => None
+                }
+            }
+
+            pub fn state_fn(&self) -> ::funfsm::StateFn<#name> {
+                match *self {
+                    #(#enum_name::#variants => ::funfsm::StateFn(#names, #states)),*
+                }
+            }
+
+            /// A registry of every state
+            pub fn registry() -> ::funfsm::registry::StateRegistry<#name> {
+                let mut registry = ::funfsm::registry::StateRegistry::new();
+                for state in #enum_name::ALL.iter() {
+                    registry.register(*state);
+                }
+                registry
+            }
+        }
+
+        impl ::funfsm::StateName for #enum_name {
+            fn state_name(&self) -> &'static str {
+                self.name()
+            }
+        }
+
+        impl From<#enum_name> for ::funfsm::StateFn<#name> {
+            fn from(state: #enum_name) -> ::funfsm::StateFn<#name> {
+                state.state_fn()
+            }
+        }
+    }
+}
+
+// Kept in step with the copy in funfsm's generator, so the types they name agree
+fn camel_case(name: &str) -> String {
+    name.split('_').filter(|part| !part.is_empty()).map(|part| {
+        let mut chars = part.chars();
+        let first = chars.next().unwrap().to_ascii_uppercase();
+        let mut word: String = first.to_string();
+        word.extend(chars);
+        word
+    }).collect()
+}
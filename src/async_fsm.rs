@@ -0,0 +1,85 @@
+//! A machine whose state functions are asynchronous, for handlers that need to wait on I/O such
+//! as a database call without blocking a thread.
+//!
+//! A handler returns a boxed future borrowing the context, which in a 2018 or later crate is
+//! written as `Box::pin(async move { ... })`. `AsyncFsm::send` returns a future that runs the
+//! handler and then moves to the state it chose, so the machine can be driven by any executor,
+//! e.g. from a task that loops over a channel of messages. No messages are handled concurrently:
+//! the future holds the machine mutably until the handler completes.
+
+use alloc::boxed::Box;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use fsm::FsmTypes;
+use outputs::Outputs;
+
+pub type BoxFuture<'a, O> = Pin<Box<dyn Future<Output = O> + Send + 'a>>;
+
+pub type AsyncHandler<T> =
+    for<'a> fn(&'a mut <T as FsmTypes>::Context, <T as FsmTypes>::Msg)
+               -> BoxFuture<'a, (AsyncStateFn<T>, Outputs<<T as FsmTypes>::Output>)>;
+
+/// The asynchronous counterpart of `StateFn`
+pub struct AsyncStateFn<T: FsmTypes>(pub &'static str, pub AsyncHandler<T>);
+
+impl<T: FsmTypes> Clone for AsyncStateFn<T> {
+    fn clone(&self) -> AsyncStateFn<T> {
+        AsyncStateFn(self.0, self.1)
+    }
+}
+
+#[macro_export]
+macro_rules! async_state_fn {
+    ($state:ident) => {
+        $crate::async_fsm::AsyncStateFn(stringify!($state), $state)
+    };
+    ($m:ident::$state:ident) => {
+        $crate::async_fsm::AsyncStateFn(stringify!($state), $m::$state)
+    }
+}
+
+pub struct AsyncFsm<T: FsmTypes> {
+    pub ctx: T::Context,
+    state: AsyncStateFn<T>
+}
+
+impl<T: FsmTypes> AsyncFsm<T> {
+    pub fn new(ctx: T::Context, state: AsyncStateFn<T>) -> AsyncFsm<T> {
+        AsyncFsm { ctx, state }
+    }
+
+    pub fn get_state(&self) -> (&'static str, &T::Context) {
+        (self.state.0, &self.ctx)
+    }
+
+    /// Handle `msg` in the current state. The transition happens when the returned future
+    /// completes. Dropping it before then leaves the machine in the state it was in.
+    pub fn send(&mut self, msg: T::Msg) -> SendFuture<'_, T> {
+        let handling = (self.state.1)(&mut self.ctx, msg);
+        SendFuture {
+            state: &mut self.state,
+            handling
+        }
+    }
+}
+
+/// The future returned by `AsyncFsm::send`, resolving to the output of the handler
+pub struct SendFuture<'a, T: FsmTypes + 'a> {
+    state: &'a mut AsyncStateFn<T>,
+    handling: BoxFuture<'a, (AsyncStateFn<T>, Outputs<T::Output>)>
+}
+
+impl<'a, T: FsmTypes> Future for SendFuture<'a, T> {
+    type Output = Outputs<T::Output>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Outputs<T::Output>> {
+        match self.handling.as_mut().poll(cx) {
+            Poll::Ready((next, output)) => {
+                *self.state = next;
+                Poll::Ready(output)
+            },
+            Poll::Pending => Poll::Pending
+        }
+    }
+}
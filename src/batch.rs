@@ -0,0 +1,91 @@
+//! Batch the output of an fsm before handing it to an external sink, so a chatty machine doesn't
+//! cost one syscall or RPC per output. A batch is flushed once it holds `max_len` outputs, once its
+//! oldest output has waited `max_age`, or as soon as the fsm finishes.
+//!
+//! Ages are measured on the fsm's own clock, so a `MockClock` set with `Fsm::set_clock` drives
+//! batching as well as timers. If the fsm buffers its output with `Fsm::buffer_outputs`, the
+//! buffer is drained into the batch after every message.
+
+use alloc::vec::Vec;
+use core::mem;
+use core::time::Duration;
+use fsm::{Fsm, FsmTypes};
+use outputs::Outputs;
+
+pub struct Batcher<T: FsmTypes, S: FnMut(Vec<T::Output>)> {
+    pub fsm: Fsm<T>,
+    sink: S,
+    max_len: usize,
+    max_age: Duration,
+    pending: Vec<T::Output>,
+    // When the oldest pending output was produced
+    started: Duration
+}
+
+impl<T: FsmTypes, S: FnMut(Vec<T::Output>)> Batcher<T, S> {
+    pub fn new(fsm: Fsm<T>, sink: S, max_len: usize, max_age: Duration) -> Batcher<T, S> {
+        assert!(max_len > 0, "The batch size must be non-zero");
+        Batcher {
+            fsm,
+            sink,
+            max_len,
+            max_age,
+            pending: Vec::new(),
+            started: Duration::from_secs(0)
+        }
+    }
+
+    /// Send a message to the fsm, adding its output to the current batch
+    pub fn send(&mut self, msg: T::Msg) {
+        let output = self.fsm.send(msg);
+        self.collect(output);
+    }
+
+    /// Deliver the fsm's due timer messages with `Fsm::poll_timers`, adding their output to the
+    /// current batch. Call this periodically, so that timers fire and a quiet machine doesn't hold
+    /// output back indefinitely.
+    pub fn poll_timers(&mut self) {
+        let output = self.fsm.poll_timers();
+        self.collect(output);
+    }
+
+    /// Flush the batch if it has waited `max_age`
+    pub fn poll(&mut self) {
+        if !self.pending.is_empty() && self.fsm.now() - self.started >= self.max_age {
+            self.flush();
+        }
+    }
+
+    /// Hand any pending output to the sink now
+    pub fn flush(&mut self) {
+        if !self.pending.is_empty() {
+            (self.sink)(mem::take(&mut self.pending));
+        }
+    }
+
+    /// The number of outputs waiting to be flushed
+    pub fn pending_len(&self) -> usize {
+        self.pending.len()
+    }
+
+    // Add output, along with anything the fsm buffered, to the batch and flush it if it's due
+    fn collect(&mut self, output: Outputs<T::Output>) {
+        let was_empty = self.pending.is_empty();
+        self.pending.extend(output);
+        self.pending.extend(self.fsm.drain_outputs());
+        if was_empty && !self.pending.is_empty() {
+            self.started = self.fsm.now();
+        }
+        if self.pending.len() >= self.max_len || self.fsm.is_finished() {
+            self.flush();
+        } else {
+            self.poll();
+        }
+    }
+}
+
+impl<T: FsmTypes, S: FnMut(Vec<T::Output>)> Drop for Batcher<T, S> {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
@@ -0,0 +1,131 @@
+//! Generate the skeleton of a new fsm: the types, two starter states, and a test module that runs
+//! the machine under a `Checker`.
+//!
+//! Usage: `funfsm new <name>`, which writes `<name>.rs` to the current directory.
+
+use std::env;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::process;
+
+const TEMPLATE: &str = r#"//! The {name} fsm. Generated by `funfsm new {name}`.
+
+use funfsm::{impl_display_state_via_debug, next, FsmTypes, Outputs, StateFn};
+
+#[derive(Debug, Clone, Default)]
+pub struct Context {
+    // The data state of the fsm goes here
+}
+
+impl_display_state_via_debug!(Context);
+
+#[derive(Debug, Clone)]
+pub enum {Name}Msg {
+    Start,
+    Stop
+}
+
+#[derive(Debug, Clone)]
+pub enum {Name}Output {}
+
+#[derive(Debug)]
+pub struct {Name}Types;
+
+impl FsmTypes for {Name}Types {
+    type Context = Context;
+    type Msg = {Name}Msg;
+    type Output = {Name}Output;
+}
+
+pub fn idle(_ctx: &mut Context, msg: {Name}Msg)
+            -> (StateFn<{Name}Types>, Outputs<{Name}Output>) {
+    match msg {
+        {Name}Msg::Start => next!(running),
+        {Name}Msg::Stop => next!(idle)
+    }
+}
+
+pub fn running(_ctx: &mut Context, msg: {Name}Msg)
+               -> (StateFn<{Name}Types>, Outputs<{Name}Output>) {
+    match msg {
+        {Name}Msg::Start => next!(running),
+        {Name}Msg::Stop => next!(idle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use funfsm::{check, state_fn, transition};
+    use funfsm::constraints::{ConstraintError, Constraints};
+    use funfsm::fsm_check::Checker;
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let mut c = Constraints::new();
+        transition!(c, "idle" => "running", idle_to_running);
+
+        let mut checker = Checker::<{Name}Types>::new(Context::default(), state_fn!(idle), c);
+        for msg in [{Name}Msg::Start, {Name}Msg::Stop] {
+            assert!(checker.check(msg).is_ok());
+        }
+    }
+
+    #[allow(unused_must_use)]
+    fn idle_to_running(_init_ctx: &Context,
+                       _final_ctx: &Context,
+                       msg: &{Name}Msg,
+                       _output: &[{Name}Output]) -> Result<(), ConstraintError>
+    {
+        let s = "Transition from idle to running";
+        check!(s, matches!(*msg, {Name}Msg::Start));
+        Ok(())
+    }
+}
+"#;
+
+fn usage() -> ! {
+    eprintln!("Usage: funfsm new <name>");
+    process::exit(2);
+}
+
+fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_lowercase() => (),
+        _ => return false
+    }
+    chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+fn camel_case(name: &str) -> String {
+    name.split('_').filter(|part| !part.is_empty()).map(|part| {
+        let mut chars = part.chars();
+        let first = chars.next().unwrap().to_ascii_uppercase();
+        let mut word: String = first.to_string();
+        word.extend(chars);
+        word
+    }).collect()
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let name = match (args.first().map(|s| &s[..]), args.get(1)) {
+        (Some("new"), Some(name)) if args.len() == 2 => name,
+        _ => usage()
+    };
+    if !is_valid_name(name) {
+        eprintln!("The name must be snake_case, e.g. `cat_bowl`");
+        process::exit(1);
+    }
+
+    let source = TEMPLATE.replace("{name}", name).replace("{Name}", &camel_case(name));
+    let path = format!("{}.rs", name);
+    let result = OpenOptions::new().write(true).create_new(true).open(&path)
+        .and_then(|mut file| file.write_all(source.as_bytes()));
+    if let Err(e) = result {
+        eprintln!("Failed to create {}: {}", path, e);
+        process::exit(1);
+    }
+    println!("Created {}. Add `mod {};` to your crate root to use it.", path, name);
+}
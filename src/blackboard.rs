@@ -0,0 +1,56 @@
+//! A shared, read-mostly store that many fsms can consult from their state functions, typically by
+//! keeping an `Arc<Blackboard<V>>` in their context. A coordinator replaces the value atomically,
+//! bumping its version and notifying subscribers, which usually forward the notification as a
+//! message to the machine they feed.
+
+use std::sync::{Arc, Mutex, RwLock};
+
+type Subscriber = Arc<dyn Fn(u64) + Send + Sync>;
+
+pub struct Blackboard<V> {
+    current: RwLock<(u64, Arc<V>)>,
+    subscribers: Mutex<Vec<Subscriber>>
+}
+
+impl<V> Blackboard<V> {
+    pub fn new(value: V) -> Blackboard<V> {
+        Blackboard {
+            current: RwLock::new((0, Arc::new(value))),
+            subscribers: Mutex::new(Vec::new())
+        }
+    }
+
+    /// Return the current version and value. The value is shared, so this never copies it.
+    pub fn read(&self) -> (u64, Arc<V>) {
+        let current = self.current.read().unwrap();
+        (current.0, current.1.clone())
+    }
+
+    pub fn version(&self) -> u64 {
+        self.current.read().unwrap().0
+    }
+
+    /// Replace the value with one computed from the current value and return the new version.
+    /// Readers see either the old or the new value, never a partial update. Subscribers are
+    /// notified after the write completes, and without any lock held, so they may read, update or
+    /// subscribe to the board.
+    pub fn update<F: FnOnce(&V) -> V>(&self, f: F) -> u64 {
+        let version = {
+            let mut current = self.current.write().unwrap();
+            let value = f(&current.1);
+            current.0 += 1;
+            current.1 = Arc::new(value);
+            current.0
+        };
+        let subscribers = self.subscribers.lock().unwrap().clone();
+        for subscriber in subscribers {
+            subscriber(version);
+        }
+        version
+    }
+
+    /// Register a callback that is passed the new version after every update
+    pub fn subscribe<F: Fn(u64) + Send + Sync + 'static>(&self, f: F) {
+        self.subscribers.lock().unwrap().push(Arc::new(f));
+    }
+}
@@ -0,0 +1,107 @@
+//! Configure an `Fsm` in a single expression instead of a sequence of calls after `Fsm::new`.
+//! Every method mirrors the `Fsm` method of the same purpose, so see those for details.
+
+use alloc::sync::Arc;
+use core::time::Duration;
+use fsm::{Fsm, FsmTypes, StateFn, Hook, GuardFn, DeferPred, MsgFn, QueueFn, TimerQueueFn,
+          MsgMiddleware, OutputMiddleware, FailurePolicy};
+use timer::Clock;
+
+pub struct FsmBuilder<T: FsmTypes> {
+    fsm: Fsm<T>
+}
+
+impl<T: FsmTypes> FsmBuilder<T> {
+    pub fn new(ctx: T::Context, state: StateFn<T>) -> FsmBuilder<T> {
+        FsmBuilder {
+            fsm: Fsm::new(ctx, state)
+        }
+    }
+
+    pub fn on_entry(mut self, state: &'static str, hook: Hook<T>) -> FsmBuilder<T> {
+        self.fsm.on_entry(state, hook);
+        self
+    }
+
+    pub fn on_exit(mut self, state: &'static str, hook: Hook<T>) -> FsmBuilder<T> {
+        self.fsm.on_exit(state, hook);
+        self
+    }
+
+    pub fn on_entry_guard(mut self, state: &'static str, acquire: GuardFn<T>) -> FsmBuilder<T> {
+        self.fsm.on_entry_guard(state, acquire);
+        self
+    }
+
+    pub fn defer(mut self, state: &'static str, pred: DeferPred<T>) -> FsmBuilder<T> {
+        self.fsm.defer(state, pred);
+        self
+    }
+
+    pub fn mark_final(mut self, state: &'static str) -> FsmBuilder<T> {
+        self.fsm.mark_final(state);
+        self
+    }
+
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> FsmBuilder<T> {
+        self.fsm.set_clock(clock);
+        self
+    }
+
+    pub fn state_timeout(mut self,
+                         state: &'static str,
+                         timeout: Duration,
+                         msg: MsgFn<T>) -> FsmBuilder<T> {
+        self.fsm.set_state_timeout(state, timeout, msg);
+        self
+    }
+
+    pub fn deadline(mut self, deadline: Duration) -> FsmBuilder<T> {
+        self.fsm.set_deadline(deadline);
+        self
+    }
+
+    pub fn failure_policy(mut self, policy: FailurePolicy<StateFn<T>>) -> FsmBuilder<T> {
+        self.fsm.set_failure_policy(policy);
+        self
+    }
+
+    pub fn internal_queue(mut self, queue: QueueFn<T>, limit: usize) -> FsmBuilder<T> {
+        self.fsm.set_internal_queue(queue, limit);
+        self
+    }
+
+    pub fn timer_queue(mut self, queue: TimerQueueFn<T>) -> FsmBuilder<T> {
+        self.fsm.set_timer_queue(queue);
+        self
+    }
+
+    pub fn msg_middleware(mut self, middleware: MsgMiddleware<T>) -> FsmBuilder<T> {
+        self.fsm.add_msg_middleware(middleware);
+        self
+    }
+
+    pub fn output_middleware(mut self, middleware: OutputMiddleware<T>) -> FsmBuilder<T> {
+        self.fsm.add_output_middleware(middleware);
+        self
+    }
+
+    pub fn buffer_outputs(mut self) -> FsmBuilder<T> {
+        self.fsm.buffer_outputs();
+        self
+    }
+
+    pub fn count_transitions(mut self) -> FsmBuilder<T> {
+        self.fsm.count_transitions();
+        self
+    }
+
+    pub fn record_history(mut self, capacity: usize) -> FsmBuilder<T> {
+        self.fsm.record_history(capacity);
+        self
+    }
+
+    pub fn build(self) -> Fsm<T> {
+        self.fsm
+    }
+}
@@ -1,31 +1,266 @@
-use std::collections::HashMap;
-use fsm::FsmTypes;
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::error::Error;
+use core::fmt;
+use fsm::{FsmTypes, StateName};
 
-pub type Pred<T: FsmTypes> = Box<Fn(&T::Context) -> bool>;
-pub type TransitionCheck<T: FsmTypes>
-    = fn(&T::Context, &T::Context, &T::Msg, &[T::Output]) -> Result<(), String>;
+/// Which constraint failed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Failure {
+    Precondition { state: &'static str, expression: &'static str },
+    Postcondition { state: &'static str, expression: &'static str },
+    Invariant { expression: &'static str },
+    StateInvariant { state: &'static str, expression: &'static str },
+    /// An `output!` constraint on the output of a single message
+    Output { expression: &'static str },
+    /// A `check!` in a transition check
+    Check { message: String, expression: &'static str, file: &'static str, line: u32 },
+    /// Every failed `Check` of a `check_all!`, in order
+    Checks(Vec<Failure>),
+    /// A transition missing from the declared set, see `Checker::require_declared_transitions`
+    Undeclared { from: &'static str, to: &'static str },
+    /// A transition registered with `forbid_transition!`
+    Forbidden { from: &'static str, to: &'static str }
+}
+
+/// A message the `Checker` sent, rendered so that it can be kept in an error
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceStep {
+    pub from: &'static str,
+    /// The `Debug` rendering of the message
+    pub msg: String,
+    pub to: &'static str,
+    /// The `Debug` rendering of the output
+    pub output: String,
+    /// The context after the message, rendered with `DisplayState`
+    pub context: String
+}
+
+impl fmt::Display for TraceStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} --{}--> {} {} Context: {}",
+               self.from, self.msg, self.to, self.output, self.context)
+    }
+}
+
+/// The default error type of `Constraints` and `Checker`. It displays the same way the errors
+/// always have, but can also be matched on. The alternate format (`{:#}`) adds the trace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConstraintError {
+    pub failure: Failure,
+    /// The context the constraint failed on, rendered with `DisplayState`. The `Checker` fills
+    /// this in.
+    pub context: Option<String>,
+    /// The messages sent before the failure, oldest first, if `Checker::record_trace` was called
+    pub trace: Vec<TraceStep>
+}
 
-pub struct Constraints<T: FsmTypes> {
-    pub preconditions: HashMap<&'static str, Vec<(Pred<T>, String)>>,
-    pub invariants: Vec<(Pred<T>, String)>,
-    pub transitions: HashMap<(&'static str, &'static str), TransitionCheck<T>>
+impl ConstraintError {
+    pub fn new(failure: Failure) -> ConstraintError {
+        ConstraintError { failure, context: None, trace: Vec::new() }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Failure::Precondition { state, expression } => {
+                write!(f, "{}", errstr("precondition", state, expression))
+            },
+            Failure::Postcondition { state, expression } => {
+                write!(f, "{}", errstr("postcondition", state, expression))
+            },
+            Failure::Invariant { expression } => write!(f, "Failed invariant: {}", expression),
+            Failure::StateInvariant { state, expression } => {
+                write!(f, "{}", errstr("invariant", state, expression))
+            },
+            Failure::Output { expression } => {
+                write!(f, "Failed output constraint: {}", expression)
+            },
+            Failure::Check { ref message, expression, file, line } => {
+                write!(f, "Error: {} Predicate: {} File: {} Line: {}",
+                       message, expression, file, line)
+            },
+            Failure::Checks(ref failures) => {
+                for (i, failure) in failures.iter().enumerate() {
+                    if i > 0 { write!(f, "; ")?; }
+                    write!(f, "{}", failure)?;
+                }
+                Ok(())
+            },
+            Failure::Undeclared { from, to } => {
+                write!(f, "Undeclared transition from {} to {}", from, to)
+            },
+            Failure::Forbidden { from, to } => {
+                write!(f, "Forbidden transition from {} to {}", from, to)
+            }
+        }
+    }
+}
+
+impl fmt::Display for ConstraintError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.failure)?;
+        if let Some(ref ctx) = self.context {
+            write!(f, " Context: {}", ctx)?;
+        }
+        if f.alternate() {
+            for (i, step) in self.trace.iter().enumerate() {
+                write!(f, "\n  {}: {}", i, step)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Error for ConstraintError {}
+
+/// An error type `Constraints` and `Checker` can report. Failures detected by the constraints
+/// themselves are built from a `ConstraintError`, while transition checks can return any value of
+/// the type.
+pub trait CheckError: From<ConstraintError> + fmt::Display {
+    /// Record the rendered context the failure happened on. Ignored by default.
+    fn set_context(&mut self, _ctx: String) {}
+
+    /// Record the steps leading up to the failure. Ignored by default.
+    fn set_trace(&mut self, _trace: Vec<TraceStep>) {}
+}
+
+impl CheckError for ConstraintError {
+    fn set_context(&mut self, ctx: String) {
+        self.context = Some(ctx);
+    }
+
+    fn set_trace(&mut self, trace: Vec<TraceStep>) {
+        self.trace = trace;
+    }
+}
+
+impl From<ConstraintError> for String {
+    fn from(err: ConstraintError) -> String {
+        err.to_string()
+    }
+}
+
+/// Plain strings, for checks written before `ConstraintError`
+impl CheckError for String {
+    fn set_context(&mut self, ctx: String) {
+        self.push_str(" Context: ");
+        self.push_str(&ctx);
+    }
+}
+
+pub type Pred<T> = Box<dyn Fn(&<T as FsmTypes>::Context) -> bool>;
+
+/// A predicate that also sees the message being handled
+pub type MsgPred<T> = BoxedMsgPred<<T as FsmTypes>::Context, <T as FsmTypes>::Msg>;
+pub type BoxedMsgPred<C, M> = Box<dyn Fn(&C, &M) -> bool>;
+
+/// Lets `precondition!` and `postcondition!` take a closure over either the context alone or the
+/// context and the message. `Args` only tells the two impls apart and is always inferred.
+pub trait IntoMsgPred<C, M, Args> {
+    fn into_msg_pred(self) -> BoxedMsgPred<C, M>;
+}
+
+impl<C, M, F> IntoMsgPred<C, M, (C,)> for F where F: Fn(&C) -> bool + 'static {
+    fn into_msg_pred(self) -> BoxedMsgPred<C, M> {
+        Box::new(move |ctx, _| self(ctx))
+    }
+}
+
+impl<C, M, F> IntoMsgPred<C, M, (C, M)> for F where F: Fn(&C, &M) -> bool + 'static {
+    fn into_msg_pred(self) -> BoxedMsgPred<C, M> {
+        Box::new(self)
+    }
+}
+
+pub type OutputPred<T> = Box<dyn Fn(&[<T as FsmTypes>::Output]) -> bool>;
+
+pub type TransitionCheck<T, E = ConstraintError> = fn(&<T as FsmTypes>::Context,
+                                                      &<T as FsmTypes>::Context,
+                                                      &<T as FsmTypes>::Msg,
+                                                      &[<T as FsmTypes>::Output])
+                                                      -> Result<(), E>;
+
+/// The constraints a `Checker` verifies. `E` is the error type transition checks return, see
+/// `CheckError`.
+pub struct Constraints<T: FsmTypes, E = ConstraintError> {
+    pub preconditions: BTreeMap<&'static str, Vec<(MsgPred<T>, ConstraintError)>>,
+    pub postconditions: BTreeMap<&'static str, Vec<(MsgPred<T>, ConstraintError)>>,
+    pub invariants: Vec<(Pred<T>, ConstraintError)>,
+    pub state_invariants: BTreeMap<&'static str, Vec<(Pred<T>, ConstraintError)>>,
+    pub outputs: Vec<(OutputPred<T>, ConstraintError)>,
+    pub transitions: BTreeMap<(&'static str, &'static str), Vec<TransitionCheck<T, E>>>,
+    /// Transitions declared without a check, see `Checker::require_declared_transitions`
+    pub declared: BTreeSet<(&'static str, &'static str)>,
+    /// Transitions that must never happen, see `forbid_transition!`
+    pub forbidden: BTreeSet<(&'static str, &'static str)>
 }
 
 impl<T: FsmTypes> Constraints<T> {
+    /// Constraints with the default error type. Use `Default` for any other.
     pub fn new() -> Constraints<T> {
-        Constraints {
-            preconditions: HashMap::new(),
-            invariants: Vec::new(),
-            transitions: HashMap::new()
+        Constraints::default()
+    }
+}
+
+impl<T: FsmTypes, E> Constraints<T, E> {
+    /// Add all of `other`'s constraints to these, e.g. to combine invariants shared by many tests
+    /// with the transition checks of one. Nothing is replaced: where both sets constrain the same
+    /// state or transition, all of the checks apply.
+    pub fn merge(&mut self, other: Constraints<T, E>) {
+        for (state, preds) in other.preconditions {
+            self.preconditions.entry(state).or_default().extend(preds);
+        }
+        for (state, preds) in other.postconditions {
+            self.postconditions.entry(state).or_default().extend(preds);
+        }
+        self.invariants.extend(other.invariants);
+        for (state, preds) in other.state_invariants {
+            self.state_invariants.entry(state).or_default().extend(preds);
+        }
+        self.outputs.extend(other.outputs);
+        for (key, checks) in other.transitions {
+            self.transitions.entry(key).or_default().extend(checks);
         }
+        self.declared.extend(other.declared);
+        self.forbidden.extend(other.forbidden);
+    }
+}
+
+impl<T: FsmTypes, E: CheckError> Constraints<T, E> {
+    /// Check the preconditions of `state` before `msg` is sent
+    pub fn check_preconditions(&self,
+                               state: &'static str,
+                               ctx: &T::Context,
+                               msg: &T::Msg) -> Result<(), E> {
+        Constraints::<T, E>::check_msg_map(&self.preconditions, state, ctx, msg)
     }
 
-    pub fn check_preconditions(&self, state: &'static str, ctx: &T::Context) -> Result<(), String> {
-        Constraints::<T>::check_map(&self.preconditions, state, ctx)
+    /// Check the postconditions of `state` after it handled `msg`, against the resulting context
+    pub fn check_postconditions(&self,
+                                state: &'static str,
+                                ctx: &T::Context,
+                                msg: &T::Msg) -> Result<(), E> {
+        Constraints::<T, E>::check_msg_map(&self.postconditions, state, ctx, msg)
     }
 
-    pub fn check_invariants(&self, ctx: &T::Context) -> Result<(), String> {
-        Constraints::<T>::check_vec(&self.invariants, ctx)
+    pub fn check_invariants(&self, ctx: &T::Context) -> Result<(), E> {
+        Constraints::<T, E>::check_vec(&self.invariants, ctx)
+    }
+
+    pub fn check_state_invariants(&self, state: &'static str, ctx: &T::Context) -> Result<(), E> {
+        Constraints::<T, E>::check_map(&self.state_invariants, state, ctx)
+    }
+
+    /// Check the output produced by a single message, whichever transition it caused
+    pub fn check_outputs(&self, output: &[T::Output]) -> Result<(), E> {
+        for (f, err) in &self.outputs {
+            if !f(output) { return Err(E::from(err.clone())); }
+        }
+        Ok(())
     }
 
     /// Verify a transition result
@@ -37,82 +272,255 @@ impl<T: FsmTypes> Constraints<T> {
     ///  `msg` is the message that caused the transition
     ///  `output` is the output messages as a result of the transition
     ///
-    ///  Returns an error string if the transition check fails
+    ///  Every check matching the transition runs, exact ones before those registered with `ANY`.
+    ///  Returns the first check's error if one fails
     pub fn check_transition(&self,
                             from: &'static str,
                             to: &'static str,
                             init_ctx: &T::Context,
                             final_ctx: &T::Context,
                             msg: &T::Msg,
-                            output: &Vec<T::Output>) -> Result<(), String>
+                            output: &[T::Output]) -> Result<(), E>
     {
-        match self.transitions.get(&(from, to)) {
-            None => Ok(()),
-            Some(check) => {
-                check(init_ctx, final_ctx, msg, output)
+        for key in Constraints::<T, E>::keys(from, to) {
+            for check in self.transitions.get(&key).into_iter().flatten() {
+                check(init_ctx, final_ctx, msg, output)?;
             }
         }
+        Ok(())
+    }
+
+    pub fn check_forbidden(&self, from: &'static str, to: &'static str) -> Result<(), E> {
+        if Constraints::<T, E>::keys(from, to).any(|key| self.forbidden.contains(&key)) {
+            return Err(E::from(ConstraintError::new(Failure::Forbidden { from, to })));
+        }
+        Ok(())
+    }
+
+    /// Whether the transition from `from` to `to` was declared, with or without a check
+    pub fn is_declared(&self, from: &'static str, to: &'static str) -> bool {
+        Constraints::<T, E>::keys(from, to).any(|key| {
+            self.declared.contains(&key) || self.transitions.contains_key(&key)
+        })
+    }
+
+    /// Whether any transition check starts in `state`, i.e. whether the context from before a
+    /// message is handled in `state` will be needed afterwards
+    pub fn has_transitions_from(&self, state: &'static str) -> bool {
+        self.transitions.keys().any(|&(from, _)| from == state || from == ANY)
+    }
+
+    // The keys a transition's checks may be registered under. Wildcards only match transitions
+    // between different states.
+    fn keys(from: &'static str, to: &'static str)
+        -> impl Iterator<Item = (&'static str, &'static str)>
+    {
+        let n = if from == to { 1 } else { 4 };
+        IntoIterator::into_iter([(from, to), (ANY, to), (from, ANY), (ANY, ANY)]).take(n)
     }
 
-    fn check_map(map: &HashMap<&'static str, Vec<(Pred<T>, String)>>,
+    fn check_map(map: &BTreeMap<&'static str, Vec<(Pred<T>, ConstraintError)>>,
                  state: &'static str,
-                 ctx: &T::Context) -> Result<(), String> {
+                 ctx: &T::Context) -> Result<(), E> {
         match map.get(state) {
             None => Ok(()),
             Some(functions) => {
-                Constraints::<T>::check_vec(functions, ctx)
+                Constraints::<T, E>::check_vec(functions, ctx)
             }
         }
     }
 
-    fn check_vec(vec: &Vec<(Pred<T>, String)>, ctx: &T::Context) -> Result<(), String> {
-        for &(ref f, ref msg) in vec {
-            if !f(ctx) { return Err((*msg).clone()); }
+    fn check_vec(vec: &[(Pred<T>, ConstraintError)], ctx: &T::Context) -> Result<(), E> {
+        for (f, err) in vec {
+            if !f(ctx) { return Err(E::from(err.clone())); }
+        }
+        Ok(())
+    }
+
+    fn check_msg_map(map: &BTreeMap<&'static str, Vec<(MsgPred<T>, ConstraintError)>>,
+                     state: &'static str,
+                     ctx: &T::Context,
+                     msg: &T::Msg) -> Result<(), E> {
+        for (f, err) in map.get(state).into_iter().flatten() {
+            if !f(ctx, msg) { return Err(E::from(err.clone())); }
         }
         Ok(())
     }
 }
 
+impl<T: FsmTypes, E> Default for Constraints<T, E> {
+    fn default() -> Constraints<T, E> {
+        Constraints {
+            preconditions: BTreeMap::new(),
+            postconditions: BTreeMap::new(),
+            invariants: Vec::new(),
+            state_invariants: BTreeMap::new(),
+            outputs: Vec::new(),
+            transitions: BTreeMap::new(),
+            declared: BTreeSet::new(),
+            forbidden: BTreeSet::new()
+        }
+    }
+}
+
 #[macro_export]
 macro_rules! check {
     ($err:expr, $predicate:expr) => {
         if $predicate {
-            let res: Result<(), String> = Ok(());
+            let res: Result<(), $crate::constraints::ConstraintError> = Ok(());
             res
         } else {
-            return Err(format!("Error: {} Predicate: {} File: {} Line: {}",
-                               $err, stringify!($predicate), file!(), line!()));
+            let failure = $crate::constraints::Failure::Check {
+                message: $err.to_string(),
+                expression: stringify!($predicate),
+                file: file!(),
+                line: line!()
+            };
+            return Err(From::from($crate::constraints::ConstraintError::new(failure)));
         }
     }
 }
 
-/// Take a constraints object ($c), the &'static str name of the state ($s), and a predicate
-/// closure ($p). Box the closure and store it into the preconditions hashmap under it's state name
-/// along with an associated error message to use if the predicate fails.
+/// Like `check!`, but evaluates every predicate in the list before returning, so that one error
+/// reports all of the predicates that failed.
+#[macro_export]
+macro_rules! check_all {
+    ($err:expr, [$($predicate:expr),+ $(,)*]) => {{
+        let mut failures = Vec::new();
+        $(
+            if !$predicate {
+                failures.push($crate::constraints::Failure::Check {
+                    message: $err.to_string(),
+                    expression: stringify!($predicate),
+                    file: file!(),
+                    line: line!()
+                });
+            }
+        )+
+        if !failures.is_empty() {
+            let failure = $crate::constraints::Failure::Checks(failures);
+            return Err(From::from($crate::constraints::ConstraintError::new(failure)));
+        }
+    }}
+}
+
+/// Take a constraints object ($c), the state ($s) as anything implementing `StateName`, and a
+/// predicate closure ($p). Box the closure and store it into the preconditions hashmap under it's
+/// state name along with an associated error message to use if the predicate fails. The closure
+/// takes the context, and optionally the message about to be sent.
 #[macro_export]
 macro_rules! precondition {
     ($c:ident, $s:expr, $p:expr) => {{
-        let f = Box::new($p);
-        let err = constraints::errstr("precondition", $s, stringify!($p));
-        let mut vec = $c.preconditions.entry($s).or_insert(Vec::new());
+        let state = $crate::StateName::state_name(&$s);
+        let f = $crate::constraints::IntoMsgPred::into_msg_pred($p);
+        let failure = $crate::constraints::Failure::Precondition {
+            state,
+            expression: stringify!($p)
+        };
+        let err = $crate::constraints::ConstraintError::new(failure);
+        let mut vec = $c.preconditions.entry(state).or_insert(Vec::new());
         vec.push((f, err));
     }}
 }
 
+/// The counterpart of `precondition!`, checked after the given state ($s) handles a message. The
+/// closure takes the resulting context, and optionally the message that was handled.
+#[macro_export]
+macro_rules! postcondition {
+    ($c:ident, $s:expr, $p:expr) => {{
+        let state = $crate::StateName::state_name(&$s);
+        let f = $crate::constraints::IntoMsgPred::into_msg_pred($p);
+        let failure = $crate::constraints::Failure::Postcondition {
+            state,
+            expression: stringify!($p)
+        };
+        let err = $crate::constraints::ConstraintError::new(failure);
+        $c.postconditions.entry(state).or_insert(Vec::new()).push((f, err));
+    }}
+}
+
 /// Pre/Postconditions are only checked in specific states. Invariants are checked in every state.
 #[macro_export]
 macro_rules! invariant {
     ($c:ident, $p:expr) => {{
         let f = Box::new($p);
-        let err = format!("Failed invariant: {}", stringify!($p));
+        let failure = $crate::constraints::Failure::Invariant { expression: stringify!($p) };
+        let err = $crate::constraints::ConstraintError::new(failure);
         $c.invariants.push((f, err));
     }}
 }
 
+/// Like `invariant!`, but only checked while the fsm is in the given state ($s), both before a
+/// message is sent and after one moves the fsm there.
+#[macro_export]
+macro_rules! state_invariant {
+    ($c:ident, $s:expr, $p:expr) => {{
+        let state = $crate::StateName::state_name(&$s);
+        let f = Box::new($p);
+        let failure = $crate::constraints::Failure::StateInvariant {
+            state,
+            expression: stringify!($p)
+        };
+        let err = $crate::constraints::ConstraintError::new(failure);
+        $c.state_invariants.entry(state).or_insert(Vec::new()).push((f, err));
+    }}
+}
+
+/// Take a constraints object ($c) and a predicate closure ($p) over the output of a message. The
+/// predicate is checked after every message, in every state.
+#[macro_export]
+macro_rules! output {
+    ($c:ident, $p:expr) => {{
+        let f = Box::new($p);
+        let failure = $crate::constraints::Failure::Output { expression: stringify!($p) };
+        let err = $crate::constraints::ConstraintError::new(failure);
+        $c.outputs.push((f, err));
+    }}
+}
+
+/// Matches any state in a transition registered with `transition!`
+pub const ANY: &str = "*";
+
+/// Register a check for the transition between two states. Without a check the transition is only
+/// declared, for `Checker::require_declared_transitions`. States are anything implementing
+/// `StateName`. Either state may be `"*"` (`ANY`) to match every other state, and the source may be
+/// a list of states, e.g. `transition!(c, ["full", "empty"] => "closed", check)`.
 #[macro_export]
 macro_rules! transition {
+    ($constraints:ident, [$($from:expr),+] => $to:expr) => {{
+        $( $constraints.declared.insert($crate::constraints::transition_key(&$from, &$to)); )+
+    }};
+    ($constraints:ident, [$($from:expr),+] => $to:expr, $check:expr) => {{
+        $( $constraints.transitions.entry($crate::constraints::transition_key(&$from, &$to))
+            .or_insert(Vec::new()).push($check); )+
+    }};
+    ($constraints:ident, $from:expr => $to:expr) => {{
+        $constraints.declared.insert($crate::constraints::transition_key(&$from, &$to));
+    }};
     ($constraints:ident, $from:expr => $to:expr, $check:expr) => {{
-        $constraints.transitions.insert(($from, $to), $check);
+        $constraints.transitions.entry($crate::constraints::transition_key(&$from, &$to))
+            .or_insert(Vec::new()).push($check);
+    }}
+}
+
+/// The names of a transition's states, as `transition!` and `forbid_transition!` store them
+pub fn transition_key<F, U>(from: &F, to: &U) -> (&'static str, &'static str)
+    where F: StateName,
+          U: StateName
+{
+    (from.state_name(), to.state_name())
+}
+
+/// Fail the check if the fsm ever moves from one state to the other. This also works for states
+/// that would stay put, e.g. `forbid_transition!(c, "empty" => "empty")`. The states may be
+/// wildcards or lists, as with `transition!`.
+#[macro_export]
+macro_rules! forbid_transition {
+    ($constraints:ident, [$($from:expr),+] => $to:expr) => {{
+        $( $constraints.forbidden.insert($crate::constraints::transition_key(&$from, &$to)); )+
+    }};
+    ($constraints:ident, $from:expr => $to:expr) => {{
+        $constraints.forbidden.insert($crate::constraints::transition_key(&$from, &$to));
     }}
 }
 
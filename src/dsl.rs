@@ -0,0 +1,79 @@
+//! A declarative alternative to writing state functions by hand, for machines that are just a
+//! transition table. See `fsm!`.
+
+/// Declare a machine as a table of transitions. This defines the types struct along with its
+/// `FsmTypes` impl, one state function per state, and a function returning `Constraints` with
+/// every declared transition registered, for use with `Checker::require_declared_transitions`.
+///
+/// Each `on` line matches a message pattern and names the next state, optionally with output.
+/// Messages a state has no line for leave it where it is. The generated state functions don't
+/// touch the context.
+///
+/// The macro can be imported by path, like any other, rather than with `#[macro_use]`.
+///
+/// ```
+/// extern crate funfsm;
+///
+/// use funfsm::{fsm, Fsm, StateFn};
+///
+/// #[derive(Debug)]
+/// pub enum Msg { Coin, Push }
+///
+/// fsm! {
+///     types Turnstile { context = (), msg = Msg, output = &'static str }
+///     constraints turnstile_constraints;
+///     state locked {
+///         on Msg::Coin => unlocked emits ["unlock"];
+///     }
+///     state unlocked {
+///         on Msg::Push => locked emits ["lock"];
+///     }
+/// }
+///
+/// fn main() {
+///     let mut fsm = Fsm::<Turnstile>::new((), StateFn("locked", locked));
+///     assert_eq!(fsm.send(Msg::Coin), vec!["unlock"]);
+///     assert_eq!(fsm.get_state().0, "unlocked");
+/// }
+/// ```
+#[macro_export]
+macro_rules! fsm {
+    (
+        types $types:ident { context = $ctx:ty, msg = $msg:ty, output = $out:ty }
+        constraints $constraints:ident;
+        $(
+            state $state:ident {
+                $( on $pat:pat => $to:ident $( emits [ $( $output:expr ),* ] )* ; )*
+            }
+        )*
+    ) => {
+        #[derive(Debug)]
+        pub struct $types;
+
+        impl $crate::FsmTypes for $types {
+            type Context = $ctx;
+            type Msg = $msg;
+            type Output = $out;
+        }
+
+        $(
+            pub fn $state(_: &mut $ctx, msg: $msg)
+                          -> ($crate::StateFn<$types>, $crate::Outputs<$out>) {
+                #[allow(unreachable_patterns)]
+                match msg {
+                    // Collecting an array means a single output doesn't allocate
+                    $( $pat => ($crate::StateFn(stringify!($to), $to),
+                                IntoIterator::into_iter([$( $( $output ),* )*])
+                                    .collect()), )*
+                    _ => ($crate::StateFn(stringify!($state), $state), $crate::Outputs::Empty)
+                }
+            }
+        )*
+
+        pub fn $constraints() -> $crate::constraints::Constraints<$types> {
+            let mut c = $crate::constraints::Constraints::new();
+            $( $( $crate::transition!(c, stringify!($state) => stringify!($to)); )* )*
+            c
+        }
+    };
+}
@@ -0,0 +1,69 @@
+//! States as trait objects. A `StateFn` is a plain function pointer, so everything a state knows
+//! has to live in the shared context. A `State` is a value instead, so it can own data that only
+//! matters while the machine is in that state, such as a retry counter. Moving to a freshly built
+//! state resets that data, while `Next::Stay` keeps it.
+
+use alloc::boxed::Box;
+use fsm::FsmTypes;
+use outputs::Outputs;
+
+/// Where a `State` goes after handling a message
+pub enum Next<T: FsmTypes> {
+    /// Remain in the current state, keeping its data
+    Stay,
+    /// Move to the given state
+    Goto(Box<dyn State<T>>)
+}
+
+pub trait State<T: FsmTypes> {
+    fn name(&self) -> &'static str;
+    fn handle(&mut self, ctx: &mut T::Context, msg: T::Msg) -> (Next<T>, Outputs<T::Output>);
+}
+
+/// A state built from a closure. The closure's captures are its state-local data.
+pub struct FnState<F> {
+    name: &'static str,
+    f: F
+}
+
+impl<F> FnState<F> {
+    pub fn new(name: &'static str, f: F) -> FnState<F> {
+        FnState { name, f }
+    }
+}
+
+impl<T, F> State<T> for FnState<F>
+    where T: FsmTypes,
+          F: FnMut(&mut T::Context, T::Msg) -> (Next<T>, Outputs<T::Output>)
+{
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn handle(&mut self, ctx: &mut T::Context, msg: T::Msg) -> (Next<T>, Outputs<T::Output>) {
+        (self.f)(ctx, msg)
+    }
+}
+
+pub struct DynFsm<T: FsmTypes> {
+    pub ctx: T::Context,
+    state: Box<dyn State<T>>
+}
+
+impl<T: FsmTypes> DynFsm<T> {
+    pub fn new(ctx: T::Context, state: Box<dyn State<T>>) -> DynFsm<T> {
+        DynFsm { ctx, state }
+    }
+
+    pub fn get_state(&self) -> (&'static str, &T::Context) {
+        (self.state.name(), &self.ctx)
+    }
+
+    pub fn send(&mut self, msg: T::Msg) -> Outputs<T::Output> {
+        let (next, output) = self.state.handle(&mut self.ctx, msg);
+        if let Next::Goto(state) = next {
+            self.state = state;
+        }
+        output
+    }
+}
@@ -0,0 +1,105 @@
+//! A variant of `Fsm` whose state functions return a `Result`, for handlers that can fail in ways
+//! that shouldn't panic or be quietly folded into the output. What happens to the machine after an
+//! error is decided by its `FailurePolicy`, and the error is always returned to the caller of
+//! `send`.
+
+use core::fmt::Debug;
+use fsm::{FailurePolicy, FsmTypes};
+use outputs::Outputs;
+
+/// Move to the next state of a `TryFsm`, optionally with output
+#[macro_export]
+macro_rules! try_next {
+     ($t:ident) => {
+         Ok((TryStateFn(stringify!($t), $t), $crate::Outputs::Empty))
+     };
+     ($t:ident, $output:expr) => {
+         Ok((TryStateFn(stringify!($t), $t), $crate::Outputs::from($output)))
+     };
+}
+
+#[macro_export]
+macro_rules! try_state_fn {
+    ($t:ident) => {
+        TryStateFn(stringify!($t), $t)
+    };
+}
+
+pub trait TryFsmTypes: FsmTypes {
+    type Error: Debug;
+}
+
+/// The signature of a fallible state function
+pub type TryHandler<T> = fn(&mut <T as FsmTypes>::Context, <T as FsmTypes>::Msg)
+                            -> Result<(TryStateFn<T>, Outputs<<T as FsmTypes>::Output>),
+                                      <T as TryFsmTypes>::Error>;
+
+/// The name of a state and its fallible state function
+pub struct TryStateFn<T: TryFsmTypes>(pub &'static str, pub TryHandler<T>);
+
+impl<T: TryFsmTypes> Clone for TryStateFn<T> {
+    fn clone(&self) -> TryStateFn<T> {
+        TryStateFn(self.0, self.1)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SendError<E> {
+    /// The state function failed
+    Failed(E),
+    /// The machine was halted by an earlier error and no longer accepts messages
+    Halted
+}
+
+pub struct TryFsm<T: TryFsmTypes> {
+    pub state: TryStateFn<T>,
+    pub ctx: T::Context,
+    policy: FailurePolicy<TryStateFn<T>>,
+    halted: bool
+}
+
+impl<T: TryFsmTypes> TryFsm<T> {
+    /// The machine starts with `FailurePolicy::Lenient`, staying in the current state after an
+    /// error
+    pub fn new(ctx: T::Context, state: TryStateFn<T>) -> TryFsm<T> {
+        TryFsm {
+            state,
+            ctx,
+            policy: FailurePolicy::Lenient,
+            halted: false
+        }
+    }
+
+    pub fn set_error_policy(&mut self, policy: FailurePolicy<TryStateFn<T>>) {
+        self.policy = policy;
+    }
+
+    pub fn get_state(&self) -> (&'static str, &T::Context) {
+        (self.state.0, &self.ctx)
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    pub fn send(&mut self, msg: T::Msg) -> Result<Outputs<T::Output>, SendError<T::Error>> {
+        if self.halted {
+            return Err(SendError::Halted);
+        }
+        match (self.state.1)(&mut self.ctx, msg) {
+            Ok((new_state, output)) => {
+                self.state = new_state;
+                Ok(output)
+            },
+            Err(err) => {
+                match self.policy {
+                    FailurePolicy::Strict => panic!("State {} failed: {:?}", self.state.0, err),
+                    FailurePolicy::Lenient => (),
+                    FailurePolicy::ErrorState(ref state) => self.state = state.clone(),
+                    FailurePolicy::Halt => self.halted = true
+                }
+                Err(SendError::Failed(err))
+            }
+        }
+    }
+}
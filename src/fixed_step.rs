@@ -0,0 +1,57 @@
+//! A driver for running an fsm inside a game loop. Each frame the loop calls `update` with the
+//! elapsed wall time, and the fsm receives any inputs queued since the last frame followed by one
+//! tick message for every whole timestep that has elapsed. Leftover time carries over to the next
+//! frame, so the simulation advances at a fixed rate regardless of the frame rate.
+
+use alloc::collections::VecDeque;
+use core::time::Duration;
+use fsm::{Fsm, FsmTypes};
+use outputs::Outputs;
+
+pub struct FixedStep<T: FsmTypes> {
+    pub fsm: Fsm<T>,
+    step: Duration,
+    tick: fn(Duration) -> T::Msg,
+    accumulator: Duration,
+    inputs: VecDeque<T::Msg>
+}
+
+impl<T: FsmTypes> FixedStep<T> {
+    /// `tick` builds the message delivered once per elapsed `step`. It is passed the step itself.
+    pub fn new(fsm: Fsm<T>, step: Duration, tick: fn(Duration) -> T::Msg) -> FixedStep<T> {
+        assert!(step > Duration::from_secs(0), "The timestep must be non-zero");
+        FixedStep {
+            fsm,
+            step,
+            tick,
+            accumulator: Duration::from_secs(0),
+            inputs: VecDeque::new()
+        }
+    }
+
+    /// Queue an input to be delivered at the start of the next frame
+    pub fn push(&mut self, msg: T::Msg) {
+        self.inputs.push_back(msg);
+    }
+
+    /// Advance the loop by `dt`, returning all output produced during the frame
+    pub fn update(&mut self, dt: Duration) -> Outputs<T::Output> {
+        let mut output = Outputs::new();
+        while let Some(msg) = self.inputs.pop_front() {
+            output.append(self.fsm.send(msg));
+        }
+        self.accumulator += dt;
+        while self.accumulator >= self.step {
+            self.accumulator -= self.step;
+            let msg = (self.tick)(self.step);
+            output.append(self.fsm.send(msg));
+        }
+        output
+    }
+
+    /// The time accumulated towards the next tick. Renderers can use this to interpolate between
+    /// simulation steps.
+    pub fn leftover(&self) -> Duration {
+        self.accumulator
+    }
+}
@@ -1,15 +1,81 @@
-use std::fmt::Debug;
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::{Drain, Vec};
+use core::any::Any;
+use core::mem;
+use core::fmt::Debug;
+use core::time::Duration;
+use builder::FsmBuilder;
+use history::{History, Metadata, Transition};
+use journal::{self, Entry, Journal};
+use outputs::Outputs;
+use stats::TransitionCounts;
+use timer::{Clock, TimerId, TimerRequest, TimerScope, Timers};
 
+/// Move to the next state, optionally with output. The output may be a `Vec` or `Outputs`. No
+/// output, or a single `Outputs::One`, costs no allocation.
 #[macro_export]
 macro_rules! next {
      ($state:ident) => {
-         (StateFn(stringify!($state), $state), Vec::new())
+         (StateFn(stringify!($state), $state), $crate::Outputs::Empty)
      };
      ($state:ident, $output:expr) => {
-         (StateFn(stringify!($state), $state), $output)
+         (StateFn(stringify!($state), $state), $crate::Outputs::from($output))
      };
 }
 
+/// Return to the state the fsm was in before the current one, e.g. to resume after an interrupt
+/// state such as `paused`. If there is no previous state the fsm stays where it is.
+#[macro_export]
+macro_rules! previous {
+     () => {
+         (StateFn($crate::fsm::PREVIOUS, $crate::fsm::previous_state), $crate::Outputs::Empty)
+     };
+     ($output:expr) => {
+         (StateFn($crate::fsm::PREVIOUS, $crate::fsm::previous_state),
+          $crate::Outputs::from($output))
+     };
+}
+
+/// Move to a terminal state that ignores every later message, optionally with output. The fsm
+/// reports `is_finished()` once it gets there.
+#[macro_export]
+macro_rules! done {
+     () => {
+         (StateFn($crate::fsm::DONE, $crate::fsm::done_state), $crate::Outputs::Empty)
+     };
+     ($output:expr) => {
+         (StateFn($crate::fsm::DONE, $crate::fsm::done_state), $crate::Outputs::from($output))
+     };
+}
+
+/// Hand a message the current state doesn't handle to a parent state function. This lets many
+/// states share the handling of common messages. A parent signals that it didn't transition
+/// anywhere by returning itself, e.g. `next!(parent)`, in which case the fsm stays in the child
+/// state. Parents may delegate to their own parents in the same way.
+///
+/// ```text
+/// pub fn connecting(ctx: &mut Context, msg: Msg) -> (StateFn<Types>, Outputs<Output>) {
+///     match msg {
+///         Msg::Connected => next!(connected),
+///         msg => parent!(session, connecting, ctx, msg)
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! parent {
+     ($parent:ident, $state:ident, $ctx:expr, $msg:expr) => {{
+         let (next, output) = $parent($ctx, $msg);
+         if next.0 == stringify!($parent) {
+             (StateFn(stringify!($state), $state), output)
+         } else {
+             (next, output)
+         }
+     }}
+}
+
 #[macro_export]
 macro_rules! state_fn {
      ($state:ident) => {
@@ -20,20 +86,134 @@ macro_rules! state_fn {
      }
 }
 
+// Only the bounds needed by every fsm live here. Anything that requires cloning or sending
+// across threads (e.g. the `Checker`, which snapshots the context) states its own bounds, so that
+// purely local machines can use non-Send, non-Clone, borrowed-data-friendly types.
 pub trait FsmTypes: Sized {
     // The application state of the fsm
-    type Context: Send + Clone + Debug;
-    type Msg: Send + Clone + Debug;
-    type Output: Send + Clone + Debug;
+    type Context;
+    type Msg: Debug;
+    type Output: Debug;
+}
+
+/// Controls how a context is rendered in constraint failures and traces. Forwarding to `Debug` is
+/// usually enough, but a hand written impl can redact fields that shouldn't end up in logs.
+pub trait DisplayState {
+    fn display_state(&self) -> String;
+}
+
+/// Implement `DisplayState` for each of the given types by forwarding to `Debug`
+#[macro_export]
+macro_rules! impl_display_state_via_debug {
+    ($($ty:ty),+) => {
+        $(
+            impl $crate::DisplayState for $ty {
+                fn display_state(&self) -> String {
+                    format!("{:?}", self)
+                }
+            }
+        )+
+    }
+}
+
+/// The signature of a state function
+pub type Handler<T> = fn(&mut <T as FsmTypes>::Context, <T as FsmTypes>::Msg)
+                         -> (StateFn<T>, Outputs<<T as FsmTypes>::Output>);
+
+/// Runs once when a machine is created with `Fsm::with_init`, choosing the starting state and
+/// returning any output the machine should produce as soon as it comes up
+pub type InitFn<T> = fn(&mut <T as FsmTypes>::Context)
+                        -> (StateFn<T>, Outputs<<T as FsmTypes>::Output>);
+
+/// A function run when a transition enters or leaves a state. It may mutate the context and
+/// return output.
+pub type Hook<T> = fn(&mut <T as FsmTypes>::Context) -> Outputs<<T as FsmTypes>::Output>;
+
+/// Acquires a resource when a transition enters a state, returning a guard that is dropped when the
+/// fsm leaves it
+pub type GuardFn<T> = fn(&mut <T as FsmTypes>::Context) -> Box<dyn Any + Send + Sync>;
+
+// The guard held for the current state. It is never read, only dropped. A clone holds nothing,
+// since a forked machine doesn't own the resources of the one it was forked from.
+struct Guard(#[allow(dead_code)] Option<Box<dyn Any + Send + Sync>>);
+
+impl Clone for Guard {
+    fn clone(&self) -> Guard {
+        Guard(None)
+    }
+}
+
+/// Decides whether a message should be deferred while in a given state
+pub type DeferPred<T> = fn(&<T as FsmTypes>::Msg) -> bool;
+
+/// Finds the queue in the context that state functions push internal messages onto
+pub type QueueFn<T> = fn(&mut <T as FsmTypes>::Context) -> &mut Vec<<T as FsmTypes>::Msg>;
+
+/// Finds the queue in the context that state functions push timer requests onto
+pub type TimerQueueFn<T> = fn(&mut <T as FsmTypes>::Context)
+                              -> &mut Vec<TimerRequest<<T as FsmTypes>::Msg>>;
+
+/// Sees each message just before it reaches the state function. It may replace the message, or
+/// drop it by returning `None`.
+pub type MsgMiddleware<T> = fn(&mut <T as FsmTypes>::Context, <T as FsmTypes>::Msg)
+                               -> Option<<T as FsmTypes>::Msg>;
+
+/// Sees the combined output of each `send` before it is returned, and may replace it
+pub type OutputMiddleware<T> = fn(&mut <T as FsmTypes>::Context, Outputs<<T as FsmTypes>::Output>)
+                                  -> Outputs<<T as FsmTypes>::Output>;
+
+// The journal messages are recorded to, and how to copy a message for it
+struct JournalSlot<T: FsmTypes> {
+    journal: Box<dyn Journal<T> + Send + Sync>,
+    clone_msg: fn(&<T as FsmTypes>::Msg) -> <T as FsmTypes>::Msg
+}
+
+/// Builds a message to deliver when a timer fires
+pub type MsgFn<T> = fn() -> <T as FsmTypes>::Msg;
+
+/// How a machine responds to a runtime failure, such as a constraint violation under a `Checker`,
+/// a state function overrunning its deadline, or an error returned by a `TryFsm` state function.
+/// An `Fsm` reads its own policy, set with `Fsm::set_failure_policy`, for every failure it detects.
+/// `S` is the type of state the machine can be moved to. Reporting the failure is left to the
+/// caller, e.g. a `ViolationHandler` closure that logs the error before choosing a policy.
+#[derive(Clone)]
+pub enum FailurePolicy<S> {
+    /// Panic, failing the machine
+    Strict,
+    /// Carry on as if nothing had failed
+    Lenient,
+    /// Move to the given (usually error) state
+    ErrorState(S),
+    /// Stop the machine. An `Fsm` moves to the terminal `done!()` state, and a `TryFsm` rejects
+    /// every later message.
+    Halt
+}
+
+/// The name of the placeholder state returned by `previous!()`. It can't clash with a real state
+/// since those are named after identifiers.
+pub const PREVIOUS: &str = "<previous>";
+
+/// The placeholder state returned by `previous!()`. The fsm replaces it with the previous state, so
+/// it is never called.
+#[doc(hidden)]
+pub fn previous_state<T: FsmTypes>(_: &mut T::Context, _: T::Msg)
+                                   -> (StateFn<T>, Outputs<T::Output>) {
+    unreachable!("previous!() must be resolved by the fsm")
+}
+
+/// The name of the terminal state entered by `done!()`
+pub const DONE: &str = "<done>";
+
+/// The terminal state entered by `done!()`
+pub fn done_state<T: FsmTypes>(_: &mut T::Context, _: T::Msg)
+                               -> (StateFn<T>, Outputs<T::Output>) {
+    done!()
 }
 
 // A recursive tuple struct indicating the name of current state and the function pointer that
 // handles messages in that that state. Calling that function returns a pair containing the next
 // state and any output.
-pub struct StateFn<T: FsmTypes>(
-    pub &'static str,
-    pub fn(&mut T::Context, T::Msg) -> (StateFn<T>, Vec<T::Output>)
-);
+pub struct StateFn<T: FsmTypes>(pub &'static str, pub Handler<T>);
 
 // Function pointers aren't `Clone` due to this [bug](https://github.com/rust-lang/rust/issues/24000)
 // Since we can't derive clone, we just implement it manually, since function pointers are `Copy`
@@ -43,28 +223,689 @@ impl<T: FsmTypes> Clone for StateFn<T> {
     }
 }
 
-#[derive(Clone)]
+/// Anything that names a state: its name, its `StateFn`, or a variant of the state enum generated
+/// by `#[derive(FsmTypes)]`. The constraint macros accept any of these.
+pub trait StateName {
+    fn state_name(&self) -> &'static str;
+}
+
+impl StateName for &'static str {
+    fn state_name(&self) -> &'static str {
+        self
+    }
+}
+
+impl<T: FsmTypes> StateName for StateFn<T> {
+    fn state_name(&self) -> &'static str {
+        self.0
+    }
+}
+
 pub struct Fsm<T: FsmTypes> {
     pub state: StateFn<T>,
-    pub ctx: T::Context
+    pub ctx: T::Context,
+    initial: StateFn<T>,
+    previous: Option<StateFn<T>>,
+    counts: Option<TransitionCounts>,
+    history: Option<History>,
+    entry_hooks: BTreeMap<&'static str, Hook<T>>,
+    exit_hooks: BTreeMap<&'static str, Hook<T>>,
+    guards: BTreeMap<&'static str, GuardFn<T>>,
+    guard: Guard,
+    deferrals: BTreeMap<&'static str, DeferPred<T>>,
+    finals: BTreeSet<&'static str>,
+    deferred: VecDeque<(T::Msg, Metadata)>,
+    timers: Timers<T>,
+    timeouts: BTreeMap<&'static str, (Duration, MsgFn<T>)>,
+    timeout_timer: Option<TimerId>,
+    deadline: Option<Duration>,
+    failure_policy: FailurePolicy<StateFn<T>>,
+    internal: Option<(QueueFn<T>, usize)>,
+    timer_queue: Option<TimerQueueFn<T>>,
+    msg_middleware: Vec<MsgMiddleware<T>>,
+    output_middleware: Vec<OutputMiddleware<T>>,
+    buffering: bool,
+    outputs: Vec<T::Output>,
+    journal: Option<JournalSlot<T>>
+}
+
+// Deriving `Clone` would require the `FsmTypes` marker type itself to be `Clone`, and the output
+// type too because of the output buffer. A clone is really just a fork.
+impl<T: FsmTypes> Clone for Fsm<T> where T::Context: Clone, T::Msg: Clone {
+    fn clone(&self) -> Fsm<T> {
+        self.fork()
+    }
 }
 
 impl<T: FsmTypes> Fsm<T> {
     pub fn new(ctx: T::Context, state: StateFn<T>) -> Fsm<T> {
         Fsm {
-            state: state,
-            ctx: ctx
+            initial: state.clone(),
+            previous: None,
+            state,
+            ctx,
+            counts: None,
+            history: None,
+            entry_hooks: BTreeMap::new(),
+            exit_hooks: BTreeMap::new(),
+            guards: BTreeMap::new(),
+            guard: Guard(None),
+            deferrals: BTreeMap::new(),
+            finals: BTreeSet::new(),
+            deferred: VecDeque::new(),
+            timers: Timers::new(),
+            timeouts: BTreeMap::new(),
+            timeout_timer: None,
+            deadline: None,
+            failure_policy: FailurePolicy::Lenient,
+            internal: None,
+            timer_queue: None,
+            msg_middleware: Vec::new(),
+            output_middleware: Vec::new(),
+            buffering: false,
+            outputs: Vec::new(),
+            journal: None
         }
     }
 
+    /// Create a machine whose starting state is chosen by running `init` on the context, returning
+    /// it along with the output of `init`. The chosen state is the one `reset` returns to, and
+    /// `init` isn't run again. It may return `done!()` to start the machine finished, but panics on
+    /// `previous!()`, since a new machine has no previous state.
+    pub fn with_init(mut ctx: T::Context, init: InitFn<T>) -> (Fsm<T>, Outputs<T::Output>) {
+        let (state, output) = init(&mut ctx);
+        assert!(state.0 != PREVIOUS, "The init function returned previous!(), but there is no \
+                                      previous state to return to");
+        (Fsm::new(ctx, state), output)
+    }
+
+    /// Start configuring a machine with an `FsmBuilder`
+    pub fn builder(ctx: T::Context, state: StateFn<T>) -> FsmBuilder<T> {
+        FsmBuilder::new(ctx, state)
+    }
+
+    /// Return the fsm to the state it was created in, with the given context. This allows reusing
+    /// a machine (e.g. for a pooled connection) instead of building a new one. Any deferred
+    /// messages, pending timers, and the current state's guard are dropped.
+    ///
+    /// The current state is left as though by a transition: its exit hook runs on the old
+    /// context, then the initial state's entry hook and guard run on the new one. Their output is
+    /// returned, or buffered if `buffer_outputs` has been called.
+    pub fn reset(&mut self, ctx: T::Context) -> Outputs<T::Output> {
+        let mut output = Outputs::new();
+        if let Some(exit) = self.exit_hooks.get(self.state.0) {
+            output.append(exit(&mut self.ctx));
+        }
+        self.guard = Guard(None);
+        self.state = self.initial.clone();
+        self.previous = None;
+        self.ctx = ctx;
+        self.deferred.clear();
+        self.timers.clear();
+        self.enter(&mut output);
+        self.finish_output(output)
+    }
+
+    /// Produce an independent copy of the machine in its current state. This lets planners and
+    /// tests explore the result of different messages from the same point without replaying the
+    /// whole history. The copy starts with no buffered output and doesn't write to the journal.
+    pub fn fork(&self) -> Fsm<T> where T::Context: Clone, T::Msg: Clone {
+        Fsm {
+            state: self.state.clone(),
+            ctx: self.ctx.clone(),
+            initial: self.initial.clone(),
+            previous: self.previous.clone(),
+            counts: self.counts.clone(),
+            history: self.history.clone(),
+            entry_hooks: self.entry_hooks.clone(),
+            exit_hooks: self.exit_hooks.clone(),
+            guards: self.guards.clone(),
+            guard: self.guard.clone(),
+            deferrals: self.deferrals.clone(),
+            finals: self.finals.clone(),
+            deferred: self.deferred.clone(),
+            timers: self.timers.clone(),
+            timeouts: self.timeouts.clone(),
+            timeout_timer: self.timeout_timer,
+            deadline: self.deadline,
+            failure_policy: self.failure_policy.clone(),
+            internal: self.internal,
+            timer_queue: self.timer_queue,
+            msg_middleware: self.msg_middleware.clone(),
+            output_middleware: self.output_middleware.clone(),
+            buffering: self.buffering,
+            outputs: Vec::new(),
+            journal: None
+        }
+    }
+
+    /// Replace this machine with one of a different type, e.g. when a handshake machine has done
+    /// its job and a session machine takes over. `build` turns this machine's context into the new
+    /// machine, configured with whatever hooks, guards and clock it needs. The new machine then
+    /// enters its state: the entry hook runs, the guard is acquired and the timeout armed. Only
+    /// then are the messages this machine deferred converted with `convert_msg` and delivered in
+    /// the order they arrived. Messages that `convert_msg` maps to `None` are dropped.
+    ///
+    /// This machine leaves its current state first, running its exit hook and dropping its guard.
+    /// Returned alongside the new machine are this machine's remaining output, i.e. anything
+    /// buffered followed by the exit hook's output, and the new machine's output: its entry hook's
+    /// followed by that of the delivered messages. Timers don't carry over, since their messages
+    /// are of the old type. They are dropped.
+    pub fn hand_off<U, B, M>(mut self,
+                             build: B,
+                             mut convert_msg: M) -> (Fsm<U>, Vec<T::Output>, Outputs<U::Output>)
+        where U: FsmTypes,
+              B: FnOnce(T::Context) -> Fsm<U>,
+              M: FnMut(T::Msg) -> Option<U::Msg>
+    {
+        let mut exit = Outputs::new();
+        if let Some(hook) = self.exit_hooks.get(self.state.0) {
+            exit.append(hook(&mut self.ctx));
+        }
+        self.guard = Guard(None);
+        let exit = self.finish_output(exit);
+        let mut last = mem::take(&mut self.outputs);
+        last.extend(exit);
+
+        let mut fsm = build(self.ctx);
+        let mut entry = Outputs::new();
+        fsm.enter(&mut entry);
+        let mut output = fsm.finish_output(entry);
+        for (msg, metadata) in self.deferred {
+            if let Some(msg) = convert_msg(msg) {
+                output.append(fsm.send_with_metadata(msg, metadata));
+            }
+        }
+        (fsm, last, output)
+    }
+
     pub fn get_state(&self) -> (&'static str, &T::Context) {
         (self.state.0, &self.ctx)
     }
 
-    pub fn send(&mut self, msg: T::Msg) -> Vec<T::Output> {
-        let StateFn(_name, f) = self.state;
-        let (new_state, output) = f(&mut self.ctx, msg);
-        self.state = new_state;
+    /// Run `hook` whenever a transition enters `state` from a different state. Registering a second
+    /// hook for the same state replaces the first.
+    pub fn on_entry(&mut self, state: &'static str, hook: Hook<T>) {
+        self.entry_hooks.insert(state, hook);
+    }
+
+    /// Run `hook` whenever a transition leaves `state` for a different state. Registering a second
+    /// hook for the same state replaces the first.
+    pub fn on_exit(&mut self, state: &'static str, hook: Hook<T>) {
+        self.exit_hooks.insert(state, hook);
+    }
+
+    /// Treat `state` as terminal, so `is_finished` returns true once the fsm reaches it
+    pub fn mark_final(&mut self, state: &'static str) {
+        self.finals.insert(state);
+    }
+
+    /// Whether the fsm has reached a state marked with `mark_final` or was moved to `done!()`
+    pub fn is_finished(&self) -> bool {
+        self.state.0 == DONE || self.finals.contains(self.state.0)
+    }
+
+    /// Call `acquire` whenever a transition enters `state` from a different state, and hold on to
+    /// the guard it returns until the fsm leaves `state`, is reset, or is dropped. Dropping the
+    /// guard happens during unwinding too, so resources such as sockets or locks are released even
+    /// if the machine panics. Guards are dropped after the exit hook runs.
+    pub fn on_entry_guard(&mut self, state: &'static str, acquire: GuardFn<T>) {
+        self.guards.insert(state, acquire);
+    }
+
+    /// While in `state`, hold back any message for which `pred` returns true instead of passing it
+    /// to the state function. Deferred messages are replayed, in the order they arrived, after the
+    /// next transition to a different state. They may be deferred again by the new state.
+    pub fn defer(&mut self, state: &'static str, pred: DeferPred<T>) {
+        self.deferrals.insert(state, pred);
+    }
+
+    /// The number of messages waiting for a state change
+    pub fn deferred_len(&self) -> usize {
+        self.deferred.len()
+    }
+
+    /// Pass every message through `middleware` before it reaches a state function, e.g. to
+    /// validate or rate limit messages in one place. Middleware runs in the order it was added,
+    /// exactly once per message: deferred messages are seen when they are replayed, not when they
+    /// are put aside. Any state it needs, such as a rate limiter's counters, lives in the context.
+    pub fn add_msg_middleware(&mut self, middleware: MsgMiddleware<T>) {
+        self.msg_middleware.push(middleware);
+    }
+
+    /// Pass the combined output of every `send` through `middleware`, e.g. to deduplicate it.
+    /// Middleware runs in the order it was added.
+    pub fn add_output_middleware(&mut self, middleware: OutputMiddleware<T>) {
+        self.output_middleware.push(middleware);
+    }
+
+    /// Let state functions send messages to their own machine by pushing them onto the queue in
+    /// the context returned by `queue`. After each message is handled the queue is drained and its
+    /// messages are delivered in order, along with the sender's metadata, before `send` returns.
+    ///
+    /// At most `limit` internal messages are delivered for each message sent from outside, since a
+    /// state that keeps feeding itself would otherwise hang the caller. Going over the limit is a
+    /// failure handled by the failure policy: the messages past the limit are dropped, and then the
+    /// fsm carries on, moves to the policy's state, or panics if the policy is `Strict`.
+    pub fn set_internal_queue(&mut self, queue: QueueFn<T>, limit: usize) {
+        self.internal = Some((queue, limit));
+    }
+
+    /// Let state functions schedule timers by pushing requests onto the queue in the context
+    /// returned by `queue`. The queue is drained after each message is handled, once the fsm has
+    /// moved to its next state, so a `TimerScope::State` timer belongs to the state being entered.
+    pub fn set_timer_queue(&mut self, queue: TimerQueueFn<T>) {
+        self.timer_queue = Some(queue);
+    }
+
+    // Schedule the timers requested by state functions since the last message
+    fn schedule_requested_timers(&mut self) {
+        let queue = match self.timer_queue {
+            Some(queue) => queue,
+            None => return
+        };
+        for request in mem::take(queue(&mut self.ctx)) {
+            match request {
+                TimerRequest::After(delay, msg, scope) => self.timers.once(delay, msg, scope),
+                TimerRequest::Every(period, msg, scope) => self.timers.every(period, msg, scope)
+            };
+        }
+    }
+
+    /// Replace the clock used for timers, e.g. with a `MockClock` in tests. This should be done
+    /// before any timers are scheduled. Without the `std` feature the default clock never moves, so
+    /// a machine that uses timers, timeouts or deadlines needs a clock of its own.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.timers.set_clock(clock);
+        self.arm_timeout();
+    }
+
+    /// The current time on the fsm's clock
+    pub fn now(&self) -> Duration {
+        self.timers.now()
+    }
+
+    /// Deliver `msg` to the fsm once `delay` has elapsed. State functions can't call this, so they
+    /// request timers through `set_timer_queue` instead.
+    pub fn send_after(&mut self, delay: Duration, msg: T::Msg, scope: TimerScope) -> TimerId {
+        self.timers.once(delay, msg, scope)
+    }
+
+    /// Deliver the message built by `msg` every `period`
+    pub fn send_every(&mut self, period: Duration, msg: MsgFn<T>, scope: TimerScope) -> TimerId {
+        self.timers.every(period, msg, scope)
+    }
+
+    /// Deliver the message built by `msg` if the fsm spends `timeout` in `state` without receiving
+    /// any message. The window restarts whenever a message is delivered and the machine stays in
+    /// `state`, and the timeout is cancelled when it moves to a different one. As with all timers,
+    /// the message is delivered by `poll_timers`.
+    pub fn set_state_timeout(&mut self, state: &'static str, timeout: Duration, msg: MsgFn<T>) {
+        self.timeouts.insert(state, (timeout, msg));
+        self.arm_timeout();
+    }
+
+    // Run everything that happens on entering the current state, adding the entry hook's output
+    fn enter(&mut self, output: &mut Outputs<T::Output>) {
+        if let Some(entry) = self.entry_hooks.get(self.state.0) {
+            output.append(entry(&mut self.ctx));
+        }
+        if let Some(acquire) = self.guards.get(self.state.0) {
+            self.guard = Guard(Some(acquire(&mut self.ctx)));
+        }
+        self.arm_timeout();
+    }
+
+    // (Re)start the timeout for the current state, if it has one
+    fn arm_timeout(&mut self) {
+        if let Some(id) = self.timeout_timer.take() {
+            self.timers.cancel(id);
+        }
+        if let Some(&(timeout, msg)) = self.timeouts.get(self.state.0) {
+            self.timeout_timer = Some(self.timers.once(timeout, msg(), TimerScope::State));
+        }
+    }
+
+    /// Time every state function, as measured by the fsm's clock, and apply the failure policy
+    /// whenever one takes longer than `deadline`. A state function can't be interrupted, so this
+    /// catches accidental blocking calls in soft real-time systems after the fact rather than
+    /// preventing them.
+    pub fn set_deadline(&mut self, deadline: Duration) {
+        self.deadline = Some(deadline);
+    }
+
+    /// Choose how the fsm responds to the failures it detects, such as a state function overrunning
+    /// its deadline. The default is `FailurePolicy::Lenient`, which carries on.
+    pub fn set_failure_policy(&mut self, policy: FailurePolicy<StateFn<T>>) {
+        self.failure_policy = policy;
+    }
+
+    // The state the failure policy moves the fsm to after a failure, if any. `Strict` panics with
+    // the description of the failure.
+    fn failure_state(&self, failure: String) -> Option<StateFn<T>> {
+        match self.failure_policy {
+            FailurePolicy::Strict => panic!("{}", failure),
+            FailurePolicy::Lenient => None,
+            FailurePolicy::ErrorState(ref state) => Some(state.clone()),
+            FailurePolicy::Halt => Some(StateFn(DONE, done_state))
+        }
+    }
+
+    /// Returns false if the timer already fired or was cancelled
+    pub fn cancel_timer(&mut self, id: TimerId) -> bool {
+        self.timers.cancel(id)
+    }
+
+    /// The time until the next timer is due, so an event loop knows how long it may sleep
+    pub fn next_timer(&self) -> Option<Duration> {
+        self.timers.next_due()
+    }
+
+    /// Remove and return the message of the earliest timer that is due without delivering it, so
+    /// that a driver such as `Checker` can deliver it itself
+    pub fn pop_due_timer(&mut self) -> Option<T::Msg> {
+        self.timers.pop_due()
+    }
+
+    /// Deliver the messages of all timers that are due, in the order they became due, and return
+    /// the combined output
+    pub fn poll_timers(&mut self) -> Outputs<T::Output> {
+        let mut output = Outputs::new();
+        while let Some(msg) = self.pop_due_timer() {
+            output.append(self.send(msg));
+        }
         output
     }
+
+    /// Collect output in a buffer instead of returning it from `send`, so that output from every
+    /// source, including timers, is consumed in one place with `take_outputs` or `drain_outputs`
+    pub fn buffer_outputs(&mut self) {
+        self.buffering = true;
+    }
+
+    /// Remove and return all buffered output, oldest first
+    pub fn take_outputs(&mut self) -> Vec<T::Output> {
+        mem::take(&mut self.outputs)
+    }
+
+    /// Drain all buffered output, oldest first
+    pub fn drain_outputs(&mut self) -> Drain<'_, T::Output> {
+        self.outputs.drain(..)
+    }
+
+    /// Record every message sent to the fsm, with the resulting state and a digest of its output,
+    /// to `journal`. Messages are journaled before output middleware runs.
+    pub fn set_journal<J>(&mut self, journal: J)
+        where J: Journal<T> + Send + Sync + 'static,
+              T::Msg: Clone
+    {
+        self.journal = Some(JournalSlot {
+            journal: Box::new(journal),
+            clone_msg: T::Msg::clone
+        });
+    }
+
+    /// Rebuild the machine's state by sending it the messages of journal entries, with the
+    /// metadata they were sent with. This usually starts from a new machine. The output is
+    /// discarded, since it was already acted upon when the entries were written, and nothing is
+    /// journaled again. Fails at the first entry whose state or output digest doesn't match, which
+    /// means the machine's behaviour has changed.
+    pub fn replay<I>(&mut self, entries: I) -> Result<(), String>
+        where I: IntoIterator<Item = Entry<T::Msg>>
+    {
+        for (i, entry) in entries.into_iter().enumerate() {
+            let output = self.deliver(entry.msg, entry.metadata);
+            if self.state.0 != entry.state {
+                return Err(format!("Replay diverged at entry {}: expected state {}, got {}",
+                                   i, entry.state, self.state.0));
+            }
+            if journal::digest(&output) != entry.digest {
+                return Err(format!("Replay diverged at entry {}: output differs", i));
+            }
+        }
+        Ok(())
+    }
+
+    /// Start counting how often each transition is taken
+    pub fn count_transitions(&mut self) {
+        if self.counts.is_none() {
+            self.counts = Some(TransitionCounts::new());
+        }
+    }
+
+    /// The transition counts, if `count_transitions` has been called
+    pub fn transition_counts(&self) -> Option<&TransitionCounts> {
+        self.counts.as_ref()
+    }
+
+    /// Keep the last `capacity` transitions in memory. Calling this again replaces any existing
+    /// history.
+    pub fn record_history(&mut self, capacity: usize) {
+        self.history = Some(History::new(capacity));
+    }
+
+    /// The recent transitions, if `record_history` has been called
+    pub fn history(&self) -> Option<&History> {
+        self.history.as_ref()
+    }
+
+    /// Jump straight to a state with the given context, so test fixtures can start a machine deep
+    /// in a scenario without replaying all the messages needed to get there. The machine is set up
+    /// as if it had just entered `state`: the old state's guard and state scoped timers are
+    /// dropped without running its exit hook, then the entry hook of `state` runs, its guard is
+    /// acquired and its timeout armed. Returns the entry hook's output.
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn set_state(&mut self, state: StateFn<T>, ctx: T::Context) -> Outputs<T::Output> {
+        let mut output = Outputs::new();
+        self.timers.cancel_state_scoped();
+        self.guard = Guard(None);
+        self.state = state;
+        self.ctx = ctx;
+        self.enter(&mut output);
+        self.finish_output(output)
+    }
+
+    /// Move to `state` without a message, as a transition would: hooks and guards run, state
+    /// scoped timers are cancelled, and `previous!()` returns to the current state. The output of
+    /// the hooks is returned, or buffered if `buffer_outputs` has been called. Nothing happens if
+    /// the fsm is already in `state`.
+    pub fn goto(&mut self, state: StateFn<T>) -> Outputs<T::Output> {
+        let mut output = Outputs::new();
+        self.move_to(state, &mut output);
+        self.finish_output(output)
+    }
+
+    // Move to `state` as `goto` does, adding the output of the hooks. Returns whether the state
+    // changed.
+    fn move_to(&mut self, state: StateFn<T>, output: &mut Outputs<T::Output>) -> bool {
+        if state.0 == self.state.0 {
+            return false;
+        }
+        self.leave_for(&state, output);
+        self.state = state;
+        self.arm_timeout();
+        true
+    }
+
+    pub fn send(&mut self, msg: T::Msg) -> Outputs<T::Output> {
+        self.send_with_metadata(msg, Metadata::new())
+    }
+
+    /// Send a message along with metadata to be recorded in the history, for auditing. The output
+    /// is returned unless `buffer_outputs` has been called.
+    ///
+    /// When the state changes, the returned output is that of the state function, followed by the
+    /// exit hook of the old state, the entry hook of the new state, and then the output of any
+    /// replayed deferred messages.
+    pub fn send_with_metadata(&mut self, msg: T::Msg, metadata: Metadata) -> Outputs<T::Output> {
+        let output = self.deliver_journaled(msg, metadata);
+        self.finish_output(output)
+    }
+
+    /// Send a batch of messages, such as recorded events replayed during recovery. Returns the
+    /// combined output and the final state. Output middleware runs once over the whole batch.
+    pub fn send_all<I>(&mut self, msgs: I) -> (Outputs<T::Output>, &'static str)
+        where I: IntoIterator<Item = T::Msg>
+    {
+        let mut output = Outputs::new();
+        for msg in msgs {
+            output.append(self.deliver_journaled(msg, Metadata::new()));
+        }
+        (self.finish_output(output), self.state.0)
+    }
+
+    // Run the output middleware, then return the output or buffer it
+    fn finish_output(&mut self, mut output: Outputs<T::Output>) -> Outputs<T::Output> {
+        for middleware in &self.output_middleware {
+            output = middleware(&mut self.ctx, output);
+        }
+        if !self.buffering {
+            return output;
+        }
+        self.outputs.extend(output);
+        Outputs::Empty
+    }
+
+    // Deliver a message and write it to the journal, if there is one
+    fn deliver_journaled(&mut self, msg: T::Msg, metadata: Metadata) -> Outputs<T::Output> {
+        let copy = self.journal.as_ref().map(|slot| ((slot.clone_msg)(&msg), metadata.clone()));
+        let output = self.deliver(msg, metadata);
+        if let (Some((msg, metadata)), Some(slot)) = (copy, self.journal.as_mut()) {
+            slot.journal.append(Entry {
+                msg,
+                metadata,
+                state: self.state.0.to_string(),
+                digest: journal::digest(&output)
+            });
+        }
+        output
+    }
+
+    // Deliver a message and then replay any deferred messages if the state changed
+    fn deliver(&mut self, msg: T::Msg, metadata: Metadata) -> Outputs<T::Output> {
+        let (mut output, changed) = self.dispatch_with_internal(msg, metadata);
+        if !changed || self.deferred.is_empty() {
+            return output;
+        }
+        let mut pending = mem::take(&mut self.deferred);
+        while let Some((msg, metadata)) = pending.pop_front() {
+            let (replayed, changed) = self.dispatch_with_internal(msg, metadata);
+            output.append(replayed);
+            if changed && !self.deferred.is_empty() {
+                // Messages deferred again during the replay arrived before the ones still pending
+                let mut requeued = mem::take(&mut self.deferred);
+                requeued.append(&mut pending);
+                pending = requeued;
+            }
+        }
+        output
+    }
+
+    // Deliver a message followed by any internal messages it queued, and those they queue in turn
+    fn dispatch_with_internal(&mut self,
+                              msg: T::Msg,
+                              metadata: Metadata) -> (Outputs<T::Output>, bool) {
+        let (queue, limit) = match self.internal {
+            Some(internal) => internal,
+            None => return self.dispatch(msg, metadata)
+        };
+        let (mut output, mut changed) = self.dispatch(msg, metadata.clone());
+        let mut delivered = 0;
+        loop {
+            let mut msgs = mem::take(queue(&mut self.ctx));
+            if msgs.is_empty() {
+                return (output, changed);
+            }
+            let overflow = delivered + msgs.len() > limit;
+            if overflow {
+                let failure = format!("State {} queued more than {} internal messages for one \
+                                       message", self.state.0, limit);
+                let state = self.failure_state(failure);
+                msgs.truncate(limit - delivered);
+                for msg in msgs {
+                    let (out, c) = self.dispatch(msg, metadata.clone());
+                    output.append(out);
+                    changed |= c;
+                }
+                queue(&mut self.ctx).clear();
+                if let Some(state) = state {
+                    changed |= self.move_to(state, &mut output);
+                }
+                return (output, changed);
+            }
+            delivered += msgs.len();
+            for msg in msgs {
+                let (out, c) = self.dispatch(msg, metadata.clone());
+                output.append(out);
+                changed |= c;
+            }
+        }
+    }
+
+    // Run everything that happens when the current state is left for `to`, adding the output of
+    // the hooks. The caller sets the state afterwards.
+    fn leave_for(&mut self, to: &StateFn<T>, output: &mut Outputs<T::Output>) {
+        self.previous = Some(self.state.clone());
+        self.timers.cancel_state_scoped();
+        if let Some(exit) = self.exit_hooks.get(self.state.0) {
+            output.append(exit(&mut self.ctx));
+        }
+        self.guard = Guard(None);
+        if let Some(entry) = self.entry_hooks.get(to.0) {
+            output.append(entry(&mut self.ctx));
+        }
+        if let Some(acquire) = self.guards.get(to.0) {
+            self.guard = Guard(Some(acquire(&mut self.ctx)));
+        }
+    }
+
+    // Deliver a single message, returning its output and whether the state changed
+    fn dispatch(&mut self, msg: T::Msg, metadata: Metadata) -> (Outputs<T::Output>, bool) {
+        let StateFn(from, f) = self.state;
+        if let Some(defer) = self.deferrals.get(from) {
+            if defer(&msg) {
+                self.deferred.push_back((msg, metadata));
+                self.arm_timeout();
+                return (Outputs::Empty, false);
+            }
+        }
+        let mut msg = msg;
+        for middleware in &self.msg_middleware {
+            msg = match middleware(&mut self.ctx, msg) {
+                Some(msg) => msg,
+                None => return (Outputs::Empty, false)
+            };
+        }
+        let msg_str = self.history.as_ref().map(|_| format!("{:?}", msg));
+        let started = self.deadline.map(|_| self.timers.now());
+        let (mut new_state, mut output) = f(&mut self.ctx, msg);
+        if let (Some(deadline), Some(started)) = (self.deadline, started) {
+            let elapsed = self.timers.now() - started;
+            if elapsed > deadline {
+                let failure = format!("State {} took {:?}, over the deadline of {:?}",
+                                      from, elapsed, deadline);
+                if let Some(state) = self.failure_state(failure) {
+                    new_state = state;
+                }
+            }
+        }
+        if new_state.0 == PREVIOUS {
+            new_state = self.previous.clone().unwrap_or_else(|| self.state.clone());
+        }
+        let changed = from != new_state.0;
+        if changed {
+            self.leave_for(&new_state, &mut output);
+        }
+        if let Some(ref mut counts) = self.counts {
+            counts.record(from, new_state.0);
+        }
+        if let (Some(history), Some(msg)) = (self.history.as_mut(), msg_str) {
+            let at = self.timers.now();
+            history.record(Transition { from, to: new_state.0, msg, metadata, at });
+        }
+        self.state = new_state;
+        self.arm_timeout();
+        self.schedule_requested_timers();
+        (output, changed)
+    }
 }
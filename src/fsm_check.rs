@@ -1,39 +1,304 @@
-use fsm::{Fsm, StateFn, FsmTypes};
-use constraints::Constraints;
+use std::mem;
+use std::sync::Arc;
+use std::time::Duration;
+use fsm::{Fsm, StateFn, FsmTypes, DisplayState, FailurePolicy, DONE, done_state};
+use outputs::Outputs;
+use constraints::{CheckError, ConstraintError, Constraints, Failure, TraceStep};
+use search::{RandomConfig, XorShift};
+use timer::{Clock, MockClock};
 
-pub struct Checker<T: FsmTypes> {
+/// Invoked when a constraint fails, so that a production system running under a `Checker` can
+/// report to its error tracker and decide how to proceed instead of just returning the error. If
+/// a precondition failed and the fsm is moved to another state, the message that was about to be
+/// sent is dropped.
+pub trait ViolationHandler<T: FsmTypes, E = ConstraintError> {
+    fn on_violation(&mut self, err: &E, fsm: &Fsm<T>) -> FailurePolicy<StateFn<T>>;
+}
+
+impl<T, E, F> ViolationHandler<T, E> for F
+    where T: FsmTypes,
+          F: FnMut(&E, &Fsm<T>) -> FailurePolicy<StateFn<T>>
+{
+    fn on_violation(&mut self, err: &E, fsm: &Fsm<T>) -> FailurePolicy<StateFn<T>> {
+        self(err, fsm)
+    }
+}
+
+// A fixed policy, so the same machine can be strict in tests and lenient in production
+impl<T: FsmTypes, E> ViolationHandler<T, E> for FailurePolicy<StateFn<T>> {
+    fn on_violation(&mut self, _: &E, _: &Fsm<T>) -> FailurePolicy<StateFn<T>> {
+        self.clone()
+    }
+}
+
+/// A step of a trace run by `Checker::check_steps`
+#[derive(Debug, Clone)]
+pub enum Step<M> {
+    /// Send a message
+    Send(M),
+    /// Advance the mock clock, delivering any timer messages that become due
+    Advance(Duration)
+}
+
+/// A message sequence that failed `Checker::check_random`, shrunk by dropping messages for as
+/// long as it kept failing
+#[derive(Debug)]
+pub struct Counterexample<M, E> {
+    pub msgs: Vec<M>,
+    pub error: E
+}
+
+/// Runs an fsm, verifying its constraints around every message. Errors are of the type the
+/// constraints' transition checks return, `ConstraintError` by default.
+pub struct Checker<T: FsmTypes, E = ConstraintError> {
     pub fsm: Fsm<T>,
-    constraints: Constraints<T>
+    constraints: Constraints<T, E>,
+    handler: Option<Box<dyn ViolationHandler<T, E>>>,
+    clock: Option<MockClock>,
+    require_declared: bool,
+    trace: Option<Vec<TraceStep>>
 }
 
-impl<T: FsmTypes> Checker<T> {
-    pub fn new(ctx: T::Context, state: StateFn<T>, constraints: Constraints<T>) -> Checker<T> {
+impl<T, E> Checker<T, E>
+    where T: FsmTypes,
+          T::Context: Clone + DisplayState,
+          T::Msg: Clone,
+          E: CheckError
+{
+    pub fn new(ctx: T::Context,
+               state: StateFn<T>,
+               constraints: Constraints<T, E>) -> Checker<T, E> {
         Checker {
             fsm: Fsm::<T>::new(ctx, state),
-            constraints: constraints
+            constraints,
+            handler: None,
+            clock: None,
+            require_declared: false,
+            trace: None
+        }
+    }
+
+    /// Record every message checked from now on, so that errors carry the steps that led up to
+    /// them. Each step renders the context, which can be slow for a large one.
+    pub fn record_trace(&mut self) {
+        self.trace = Some(Vec::new());
+    }
+
+    /// The steps recorded since `record_trace` was called, oldest first
+    pub fn trace(&self) -> Option<&[TraceStep]> {
+        self.trace.as_ref().map(|trace| &trace[..])
+    }
+
+    /// Treat the transitions registered with `transition!` as the complete set the machine may
+    /// take, so that any other transition between two different states is reported as a violation.
+    /// This keeps the declared transitions from drifting away from the code.
+    pub fn require_declared_transitions(&mut self) {
+        self.require_declared = true;
+    }
+
+    /// Drive the fsm's timers from `clock`. Constraints can refer to simulated time by capturing a
+    /// clone of the same clock.
+    pub fn set_clock(&mut self, clock: MockClock) {
+        self.fsm.set_clock(Arc::new(clock.clone()));
+        self.clock = Some(clock);
+    }
+
+    /// Advance the mock clock by `by`. Each timer message is checked like any other, and is
+    /// delivered with the clock set to the moment it became due.
+    ///
+    /// Panics if `set_clock` hasn't been called.
+    pub fn advance(&mut self, by: Duration) -> Result<Outputs<T::Output>, E> {
+        let clock = self.clock.clone().expect("Checker::advance requires a clock from set_clock");
+        let end = clock.now() + by;
+        let mut output = Outputs::new();
+        loop {
+            match self.fsm.next_timer() {
+                Some(wait) if clock.now() + wait <= end => clock.advance(wait),
+                _ => break
+            }
+            while let Some(msg) = self.fsm.pop_due_timer() {
+                output.append(self.check(msg)?);
+            }
+        }
+        clock.advance(end - clock.now());
+        Ok(output)
+    }
+
+    /// Run a trace of messages and clock advances, stopping at the first error
+    pub fn check_steps<I>(&mut self, steps: I) -> Result<Outputs<T::Output>, E>
+        where I: IntoIterator<Item = Step<T::Msg>>
+    {
+        let mut output = Outputs::new();
+        for step in steps {
+            output.append(match step {
+                Step::Send(msg) => self.check(msg)?,
+                Step::Advance(by) => self.advance(by)?
+            });
         }
+        Ok(output)
     }
 
-    pub fn check(&mut self, msg: T::Msg) -> Result<Vec<T::Output>, String> {
-        let (from, init_ctx) = try!(self.check_preconditions());
-        let output = self.fsm.send(msg.clone());
-        self.check_postconditions(from, &init_ctx, &msg, &output).map(|_| output)
+    /// Check random sequences of messages drawn from `candidates`, each sent to a fork of the
+    /// machine as it is now. If every sequence passes the machine is left untouched. A failing
+    /// sequence is shrunk and returned, and the machine is replaced by the fork that sequence fails
+    /// on, which has none of the original's journal, buffered output or guard.
+    pub fn check_random(&mut self,
+                        candidates: &[T::Msg],
+                        config: &RandomConfig) -> Result<(), Counterexample<T::Msg, E>> {
+        if candidates.is_empty() {
+            return Ok(());
+        }
+        let fork = self.fsm.fork();
+        let start = mem::replace(&mut self.fsm, fork);
+        let trace = self.trace.as_mut().map(mem::take);
+        let mut rng = XorShift(config.seed.max(1));
+        for _ in 0..config.runs {
+            let msgs: Vec<_> = (0..config.length).map(|_| {
+                candidates[rng.below(candidates.len())].clone()
+            }).collect();
+            if let Err(error) = self.check_from(&start, &msgs) {
+                return Err(self.shrink(&start, msgs, error));
+            }
+        }
+        self.fsm = start;
+        self.trace = trace;
+        Ok(())
+    }
+
+    fn check_from(&mut self, start: &Fsm<T>, msgs: &[T::Msg]) -> Result<(), E> {
+        self.fsm = start.fork();
+        if let Some(ref mut trace) = self.trace {
+            trace.clear();
+        }
+        for msg in msgs {
+            self.check(msg.clone())?;
+        }
+        Ok(())
     }
 
-    pub fn check_preconditions(&self) -> Result<(&'static str, T::Context), String> {
+    fn shrink(&mut self,
+              start: &Fsm<T>,
+              mut msgs: Vec<T::Msg>,
+              mut error: E) -> Counterexample<T::Msg, E> {
+        let mut i = 0;
+        while i < msgs.len() {
+            let mut shorter = msgs.clone();
+            shorter.remove(i);
+            match self.check_from(start, &shorter) {
+                Ok(()) => i += 1,
+                Err(e) => {
+                    msgs = shorter;
+                    error = e;
+                }
+            }
+        }
+        // The last run from a shorter sequence may have passed
+        let _ = self.check_from(start, &msgs);
+        Counterexample { msgs, error }
+    }
+
+    /// Once a handler is set `check` no longer returns constraint errors. The handler decides
+    /// whether to panic, jump to another state, or continue.
+    pub fn set_violation_handler<H>(&mut self, handler: H)
+        where H: ViolationHandler<T, E> + 'static
+    {
+        self.handler = Some(Box::new(handler));
+    }
+
+    /// The context is only cloned before sending if a transition check from the current state
+    /// needs to compare it with the context afterwards.
+    pub fn check(&mut self, msg: T::Msg) -> Result<Outputs<T::Output>, E> {
+        let from = match self.check_preconditions(&msg) {
+            Ok(from) => from,
+            Err(err) => {
+                if let Some(output) = self.handle_violation(err)? {
+                    return Ok(output);
+                }
+                self.fsm.state.0
+            }
+        };
+        let init_ctx = if self.constraints.has_transitions_from(from) {
+            Some(self.fsm.ctx.clone())
+        } else {
+            None
+        };
+        let mut output = self.fsm.send(msg.clone());
+        if let Some(ref mut trace) = self.trace {
+            let (to, ctx) = self.fsm.get_state();
+            trace.push(TraceStep {
+                from,
+                msg: format!("{:?}", msg),
+                to,
+                output: format!("{:?}", output),
+                context: ctx.display_state()
+            });
+        }
+        if let Err(err) = self.check_postconditions(from, init_ctx.as_ref(), &msg, &output) {
+            output.append(self.handle_violation(err)?.unwrap_or_default());
+        }
+        Ok(output)
+    }
+
+    // Returns the output of moving to a new state if the handler chose to, or the error if there
+    // is no handler
+    fn handle_violation(&mut self, mut err: E) -> Result<Option<Outputs<T::Output>>, E> {
+        if let Some(ref trace) = self.trace {
+            err.set_trace(trace.clone());
+        }
+        let action = match self.handler {
+            Some(ref mut handler) => handler.on_violation(&err, &self.fsm),
+            None => return Err(err)
+        };
+        match action {
+            FailurePolicy::Strict => panic!("{}", err),
+            FailurePolicy::Lenient => Ok(None),
+            FailurePolicy::ErrorState(state) => Ok(Some(self.fsm.goto(state))),
+            FailurePolicy::Halt => Ok(Some(self.fsm.goto(StateFn(DONE, done_state))))
+        }
+    }
+
+    /// Errors are given the rendered context, so that the failing data state is visible. Returns
+    /// the current state if the checks pass.
+    pub fn check_preconditions(&self, msg: &T::Msg) -> Result<&'static str, E> {
         let (from, ctx) = self.fsm.get_state();
-        try!(self.constraints.check_preconditions(from, &ctx));
-        try!(self.constraints.check_invariants(&ctx));
-        Ok((from, ctx.clone()))
+        self.constraints.check_preconditions(from, ctx, msg)
+            .and_then(|_| self.constraints.check_invariants(ctx))
+            .and_then(|_| self.constraints.check_state_invariants(from, ctx))
+            .map_err(|err| with_context(err, ctx))?;
+        Ok(from)
     }
 
+    /// Transition checks are skipped without `init_ctx`, the context from before the message.
     pub fn check_postconditions(&self,
                                 from: &'static str,
-                                init_ctx: &T::Context,
+                                init_ctx: Option<&T::Context>,
                                 msg: &T::Msg,
-                                output: &Vec<T::Output>) -> Result<(), String> {
+                                output: &[T::Output]) -> Result<(), E> {
         let (to, final_ctx) = self.fsm.get_state();
-        try!(self.constraints.check_invariants(&final_ctx));
-        self.constraints.check_transition(from, to, init_ctx, final_ctx, msg, output)
+        self.constraints.check_invariants(final_ctx)
+            .and_then(|_| self.constraints.check_state_invariants(to, final_ctx))
+            .and_then(|_| self.constraints.check_postconditions(from, final_ctx, msg))
+            .and_then(|_| self.constraints.check_outputs(output))
+            .and_then(|_| self.check_declared(from, to))
+            .and_then(|_| self.constraints.check_forbidden(from, to))
+            .and_then(|_| match init_ctx {
+                Some(init_ctx) => {
+                    self.constraints.check_transition(from, to, init_ctx, final_ctx, msg, output)
+                },
+                None => Ok(())
+            })
+            .map_err(|err| with_context(err, final_ctx))
+    }
+
+    fn check_declared(&self, from: &'static str, to: &'static str) -> Result<(), E> {
+        if self.require_declared && from != to && !self.constraints.is_declared(from, to) {
+            return Err(E::from(ConstraintError::new(Failure::Undeclared { from, to })));
+        }
+        Ok(())
     }
 }
+
+fn with_context<E: CheckError, C: DisplayState>(mut err: E, ctx: &C) -> E {
+    err.set_context(ctx.display_state());
+    err
+}
@@ -0,0 +1,170 @@
+//! A bounded, in-memory record of the most recent transitions an fsm has taken, so a live process
+//! can answer "what did this machine just do?" without full persistence.
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::collections::vec_deque::Iter;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::time::Duration;
+
+/// Opaque key/value data attached by a sender, such as a user or request id, recorded alongside
+/// the transition the message caused
+pub type Metadata = BTreeMap<String, String>;
+
+#[derive(Debug, Clone)]
+pub struct Transition {
+    pub from: &'static str,
+    pub to: &'static str,
+    /// The `Debug` rendering of the message that caused the transition
+    pub msg: String,
+    pub metadata: Metadata,
+    /// When the transition happened, as read from the fsm's clock
+    pub at: Duration
+}
+
+impl Transition {
+    /// Whether two transitions are the same step, ignoring when they happened and any metadata
+    pub fn same_step(&self, other: &Transition) -> bool {
+        self.from == other.from && self.to == other.to && self.msg == other.msg
+    }
+}
+
+impl fmt::Display for Transition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} --{}--> {}", self.from, self.msg, self.to)
+    }
+}
+
+/// A ring buffer holding the last `capacity` transitions, oldest first
+#[derive(Debug, Clone)]
+pub struct History {
+    capacity: usize,
+    entries: VecDeque<Transition>
+}
+
+impl History {
+    pub fn new(capacity: usize) -> History {
+        History {
+            capacity,
+            entries: VecDeque::with_capacity(capacity)
+        }
+    }
+
+    pub fn record(&mut self, transition: Transition) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(transition);
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> Iter<'_, Transition> {
+        self.entries.iter()
+    }
+
+    /// The most recent transition, if any
+    pub fn last(&self) -> Option<&Transition> {
+        self.entries.back()
+    }
+
+    /// All recorded transitions matching the predicate, oldest first
+    pub fn filter<P>(&self, predicate: P) -> Vec<&Transition> where P: Fn(&Transition) -> bool {
+        self.entries.iter().filter(|t| predicate(t)).collect()
+    }
+
+    /// All recorded transitions into `state`
+    pub fn entering(&self, state: &str) -> Vec<&Transition> {
+        self.filter(|t| t.to == state)
+    }
+
+    /// All recorded transitions at or after `at` on the fsm's clock
+    pub fn since(&self, at: Duration) -> Vec<&Transition> {
+        self.filter(|t| t.at >= at)
+    }
+}
+
+impl<'a> IntoIterator for &'a History {
+    type Item = &'a Transition;
+    type IntoIter = Iter<'a, Transition>;
+
+    fn into_iter(self) -> Iter<'a, Transition> {
+        self.entries.iter()
+    }
+}
+
+/// The first point at which two traces differ
+#[derive(Debug)]
+pub struct Divergence<'a> {
+    /// The position of the first differing step
+    pub index: usize,
+    /// The matching steps leading up to the divergence, oldest first
+    pub common: Vec<&'a Transition>,
+    /// The differing steps. One is `None` if that trace ended first.
+    pub left: Option<&'a Transition>,
+    pub right: Option<&'a Transition>
+}
+
+impl<'a> fmt::Display for Divergence<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Traces diverge at step {}", self.index)?;
+        let start = self.index - self.common.len();
+        for (i, t) in self.common.iter().enumerate() {
+            writeln!(f, "  {}: {}", start + i, t)?;
+        }
+        for (side, t) in [("left", self.left), ("right", self.right)] {
+            match t {
+                Some(t) => writeln!(f, "  {} {}: {}", side, self.index, t)?,
+                None => writeln!(f, "  {} ended", side)?
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Compare two traces, e.g. the histories of two versions of a machine fed the same messages, and
+/// return the first step at which they differ along with up to `context` preceding steps. Returns
+/// `None` if the traces are identical.
+pub fn first_divergence<'a, L, R>(left: L, right: R, context: usize) -> Option<Divergence<'a>>
+    where L: IntoIterator<Item = &'a Transition>,
+          R: IntoIterator<Item = &'a Transition>
+{
+    let mut common = VecDeque::with_capacity(context);
+    let mut left = left.into_iter();
+    let mut right = right.into_iter();
+    let mut index = 0;
+    loop {
+        match (left.next(), right.next()) {
+            (None, None) => return None,
+            (Some(l), Some(r)) if l.same_step(r) => {
+                if context > 0 {
+                    if common.len() == context {
+                        common.pop_front();
+                    }
+                    common.push_back(l);
+                }
+                index += 1;
+            },
+            (l, r) => return Some(Divergence {
+                index,
+                common: common.into_iter().collect(),
+                left: l,
+                right: r
+            })
+        }
+    }
+}
@@ -0,0 +1,82 @@
+//! Event sourcing. A journal records every message sent to an fsm along with the state it led to
+//! and a digest of its output, so that `Fsm::replay` can rebuild the machine from the log and
+//! detect any step where the code no longer behaves as it did when the entry was written.
+
+use alloc::string::String;
+#[cfg(feature = "std")]
+use alloc::sync::Arc;
+#[cfg(feature = "std")]
+use alloc::vec::Vec;
+use core::fmt::Debug;
+#[cfg(feature = "std")]
+use std::sync::Mutex;
+use fsm::FsmTypes;
+use history::Metadata;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry<M> {
+    pub msg: M,
+    /// The metadata the message was sent with, which `Fsm::replay` sends it with again
+    pub metadata: Metadata,
+    /// The state the fsm was in after handling the message
+    pub state: String,
+    /// The `digest` of the output the message produced
+    pub digest: u64
+}
+
+/// Where journal entries are written, e.g. a file or a database table
+pub trait Journal<T: FsmTypes> {
+    fn append(&mut self, entry: Entry<T::Msg>);
+}
+
+/// A journal kept in memory. Clones share the same entries, so a test can keep one handle and give
+/// another to the fsm.
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub struct MemoryJournal<M> {
+    entries: Arc<Mutex<Vec<Entry<M>>>>
+}
+
+#[cfg(feature = "std")]
+impl<M> MemoryJournal<M> {
+    pub fn new() -> MemoryJournal<M> {
+        MemoryJournal {
+            entries: Arc::new(Mutex::new(Vec::new()))
+        }
+    }
+
+    pub fn entries(&self) -> Vec<Entry<M>> where M: Clone {
+        self.entries.lock().unwrap().clone()
+    }
+}
+
+#[cfg(feature = "std")]
+impl<M> Clone for MemoryJournal<M> {
+    fn clone(&self) -> MemoryJournal<M> {
+        MemoryJournal {
+            entries: self.entries.clone()
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<M> Default for MemoryJournal<M> {
+    fn default() -> MemoryJournal<M> {
+        MemoryJournal::new()
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: FsmTypes> Journal<T> for MemoryJournal<T::Msg> {
+    fn append(&mut self, entry: Entry<T::Msg>) {
+        self.entries.lock().unwrap().push(entry);
+    }
+}
+
+/// A digest of some output, computed as the 64 bit FNV-1a hash of its `Debug` rendering so that it
+/// is stable across runs
+pub fn digest<O: Debug>(output: &[O]) -> u64 {
+    format!("{:?}", output).bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
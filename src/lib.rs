@@ -1,10 +1,56 @@
+// Without `std`, the core machine, its constraints and the modules built on them only need
+// `alloc`. Set a clock with `Fsm::set_clock`, since `SystemClock` needs `std`.
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(feature = "std")]
+extern crate core;
+#[cfg(feature = "std")]
+extern crate alloc;
+#[cfg(not(feature = "std"))]
+#[macro_use]
+extern crate alloc;
+#[cfg(feature = "rayon")]
+extern crate rayon;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "derive")]
+extern crate funfsm_derive;
+
 #[macro_use]
 pub mod fsm;
+pub mod outputs;
 pub mod constraints;
+#[cfg(feature = "std")]
 pub mod fsm_check;
+pub mod history;
+pub mod stats;
+mod test_table;
+pub mod timer;
+#[cfg(feature = "std")]
+pub mod blackboard;
+pub mod fixed_step;
+#[cfg(feature = "std")]
+pub mod search;
+pub mod regions;
+pub mod fallible;
+pub mod dyn_state;
+pub mod batch;
+pub mod builder;
+pub mod journal;
+pub mod registry;
+mod dsl;
+pub mod async_fsm;
+#[cfg(all(feature = "std", feature = "serde"))]
+pub mod persist;
 
 pub use fsm::{
     Fsm,
     StateFn,
-    FsmTypes
+    FsmTypes,
+    DisplayState,
+    StateName
 };
+pub use outputs::Outputs;
+
+#[cfg(feature = "derive")]
+pub use funfsm_derive::FsmTypes;
@@ -0,0 +1,169 @@
+//! The output of a state function. Most transitions produce no output or a single one, so those
+//! cases are stored inline and only a state returning several outputs allocates. This matters for
+//! machines that run once per network packet.
+//!
+//! `Outputs` dereferences to a slice and compares equal to a `Vec` with the same elements, so
+//! callers can mostly treat it as one.
+
+use alloc::vec::{self, Vec};
+use core::fmt;
+use core::iter::FromIterator;
+use core::mem;
+use core::ops::Deref;
+use core::slice;
+
+/// Build a single output with `Outputs::One`, e.g. `next!(open, Outputs::One(reply))`, to avoid
+/// the allocation `vec![reply]` would make
+#[derive(Clone, Default)]
+pub enum Outputs<O> {
+    #[default]
+    Empty,
+    One(O),
+    Many(Vec<O>)
+}
+
+impl<O> Outputs<O> {
+    pub fn new() -> Outputs<O> {
+        Outputs::Empty
+    }
+
+    /// Add an output. Only the second output allocates.
+    pub fn push(&mut self, output: O) {
+        *self = match mem::take(self) {
+            Outputs::Empty => Outputs::One(output),
+            Outputs::One(first) => Outputs::Many(vec![first, output]),
+            Outputs::Many(mut outputs) => {
+                outputs.push(output);
+                Outputs::Many(outputs)
+            }
+        };
+    }
+
+    /// Move all of `other` onto the end. This doesn't allocate if either side is empty.
+    pub fn append(&mut self, other: Outputs<O>) {
+        match other {
+            Outputs::Empty => (),
+            Outputs::One(output) => self.push(output),
+            other => match *self {
+                Outputs::Empty => *self = other,
+                _ => self.extend(other)
+            }
+        }
+    }
+
+    pub fn as_slice(&self) -> &[O] {
+        match *self {
+            Outputs::Empty => &[],
+            Outputs::One(ref output) => slice::from_ref(output),
+            Outputs::Many(ref outputs) => outputs
+        }
+    }
+
+    pub fn into_vec(self) -> Vec<O> {
+        match self {
+            Outputs::Empty => Vec::new(),
+            Outputs::One(output) => vec![output],
+            Outputs::Many(outputs) => outputs
+        }
+    }
+}
+
+impl<O> Deref for Outputs<O> {
+    type Target = [O];
+
+    fn deref(&self) -> &[O] {
+        self.as_slice()
+    }
+}
+
+// Rendered like a `Vec`, so digests and failure messages don't depend on the representation
+impl<O: fmt::Debug> fmt::Debug for Outputs<O> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl<O> From<Vec<O>> for Outputs<O> {
+    fn from(outputs: Vec<O>) -> Outputs<O> {
+        if outputs.is_empty() {
+            Outputs::Empty
+        } else {
+            Outputs::Many(outputs)
+        }
+    }
+}
+
+impl<O> Extend<O> for Outputs<O> {
+    fn extend<I: IntoIterator<Item = O>>(&mut self, iter: I) {
+        for output in iter {
+            self.push(output);
+        }
+    }
+}
+
+impl<O> FromIterator<O> for Outputs<O> {
+    fn from_iter<I: IntoIterator<Item = O>>(iter: I) -> Outputs<O> {
+        let mut outputs = Outputs::Empty;
+        outputs.extend(iter);
+        outputs
+    }
+}
+
+/// An owning iterator over `Outputs`
+pub enum IntoIter<O> {
+    One(Option<O>),
+    Many(vec::IntoIter<O>)
+}
+
+impl<O> Iterator for IntoIter<O> {
+    type Item = O;
+
+    fn next(&mut self) -> Option<O> {
+        match *self {
+            IntoIter::One(ref mut output) => output.take(),
+            IntoIter::Many(ref mut outputs) => outputs.next()
+        }
+    }
+}
+
+impl<O> IntoIterator for Outputs<O> {
+    type Item = O;
+    type IntoIter = IntoIter<O>;
+
+    fn into_iter(self) -> IntoIter<O> {
+        match self {
+            Outputs::Empty => IntoIter::One(None),
+            Outputs::One(output) => IntoIter::One(Some(output)),
+            Outputs::Many(outputs) => IntoIter::Many(outputs.into_iter())
+        }
+    }
+}
+
+impl<'a, O> IntoIterator for &'a Outputs<O> {
+    type Item = &'a O;
+    type IntoIter = slice::Iter<'a, O>;
+
+    fn into_iter(self) -> slice::Iter<'a, O> {
+        self.iter()
+    }
+}
+
+impl<O: PartialEq<U>, U> PartialEq<Outputs<U>> for Outputs<O> {
+    fn eq(&self, other: &Outputs<U>) -> bool {
+        self[..] == other[..]
+    }
+}
+
+impl<O: Eq> Eq for Outputs<O> {}
+
+impl<O: PartialEq<U>, U> PartialEq<Vec<U>> for Outputs<O> {
+    fn eq(&self, other: &Vec<U>) -> bool {
+        self[..] == other[..]
+    }
+}
+
+impl<O, U: PartialEq<O>> PartialEq<Outputs<O>> for Vec<U> {
+    fn eq(&self, other: &Outputs<O>) -> bool {
+        self[..] == other[..]
+    }
+}
@@ -0,0 +1,35 @@
+//! Save a machine's state and context so it can be rebuilt after a restart. A `Snapshot` holds the
+//! name of the current state and the context, and can be serialized with any serde format. Since
+//! state functions can't be serialized, a `StateRegistry` maps the name back to its `StateFn`.
+//!
+//! Only the state and context are saved. Hooks, timers and other configuration belong to the code
+//! rather than the data, so they must be set up again on the restored machine.
+
+use serde::{Serialize, Deserialize};
+use fsm::{Fsm, FsmTypes};
+pub use registry::StateRegistry;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot<C> {
+    pub state: String,
+    pub ctx: C
+}
+
+impl<T: FsmTypes> Fsm<T> {
+    pub fn snapshot(&self) -> Snapshot<T::Context> where T::Context: Clone {
+        Snapshot {
+            state: self.state.0.to_string(),
+            ctx: self.ctx.clone()
+        }
+    }
+
+    /// Rebuild a machine from a snapshot. The restored state is also the state the machine returns
+    /// to on `reset`. Fails if the snapshot's state isn't in the registry.
+    pub fn restore(snapshot: Snapshot<T::Context>,
+                   registry: &StateRegistry<T>) -> Result<Fsm<T>, String> {
+        match registry.get(&snapshot.state) {
+            Some(state) => Ok(Fsm::new(snapshot.ctx, state)),
+            None => Err(format!("Unknown state {} in snapshot", snapshot.state))
+        }
+    }
+}
@@ -0,0 +1,51 @@
+//! Orthogonal regions: several independent state machines sharing one context. Every message is
+//! dispatched to each region in turn, so a device with separate power and connectivity states can
+//! be modelled as two small machines instead of their cross product.
+
+use alloc::vec::Vec;
+use fsm::{FsmTypes, StateFn, PREVIOUS};
+use outputs::Outputs;
+
+struct Region<T: FsmTypes> {
+    state: StateFn<T>,
+    previous: Option<StateFn<T>>
+}
+
+pub struct Regions<T: FsmTypes> {
+    pub ctx: T::Context,
+    regions: Vec<Region<T>>
+}
+
+impl<T: FsmTypes> Regions<T> where T::Msg: Clone {
+    /// Create a machine with one region per initial state
+    pub fn new(ctx: T::Context, states: Vec<StateFn<T>>) -> Regions<T> {
+        Regions {
+            ctx,
+            regions: states.into_iter().map(|state| Region { state, previous: None }).collect()
+        }
+    }
+
+    /// The current state of each region, in the order the regions were given
+    pub fn get_state(&self) -> (Vec<&'static str>, &T::Context) {
+        (self.regions.iter().map(|r| r.state.0).collect(), &self.ctx)
+    }
+
+    /// Dispatch `msg` to every region in order. Each region sees any changes the earlier ones made
+    /// to the context. The output of all regions is concatenated in region order.
+    pub fn send(&mut self, msg: T::Msg) -> Outputs<T::Output> {
+        let mut output = Outputs::new();
+        for region in &mut self.regions {
+            let StateFn(from, f) = region.state;
+            let (mut new_state, out) = f(&mut self.ctx, msg.clone());
+            if new_state.0 == PREVIOUS {
+                new_state = region.previous.clone().unwrap_or_else(|| region.state.clone());
+            }
+            if from != new_state.0 {
+                region.previous = Some(region.state.clone());
+            }
+            region.state = new_state;
+            output.append(out);
+        }
+        output
+    }
+}
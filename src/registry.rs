@@ -0,0 +1,34 @@
+//! Look up a machine's states by name, e.g. to restore a `persist::Snapshot`. `#[derive(FsmTypes)]`
+//! can generate a registry of every state along with the state enum.
+
+use alloc::collections::BTreeMap;
+use fsm::{FsmTypes, StateFn};
+
+/// The states of a machine, by name
+pub struct StateRegistry<T: FsmTypes> {
+    states: BTreeMap<&'static str, StateFn<T>>
+}
+
+impl<T: FsmTypes> StateRegistry<T> {
+    pub fn new() -> StateRegistry<T> {
+        StateRegistry {
+            states: BTreeMap::new()
+        }
+    }
+
+    /// Register a state, usually built with `state_fn!` or from a variant of a derived state enum
+    pub fn register<S: Into<StateFn<T>>>(&mut self, state: S) {
+        let state = state.into();
+        self.states.insert(state.0, state);
+    }
+
+    pub fn get(&self, name: &str) -> Option<StateFn<T>> {
+        self.states.get(name).cloned()
+    }
+}
+
+impl<T: FsmTypes> Default for StateRegistry<T> {
+    fn default() -> StateRegistry<T> {
+        StateRegistry::new()
+    }
+}
@@ -0,0 +1,247 @@
+//! Explore what an fsm would do under different message sequences by searching over forked
+//! machines. This is useful for planning (which action should I take next?) as well as for finding
+//! interesting test scenarios.
+
+use std::cmp::Ordering;
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+use fsm::{Fsm, FsmTypes};
+use outputs::Outputs;
+
+/// Explore every sequence of exactly `depth` messages drawn from `candidates`, starting from a fork
+/// of `fsm`. Each leaf machine is scored with `score` and the best sequence is returned along with
+/// its score. Sibling subtrees are explored in parallel.
+///
+/// Returns `None` if there are no candidates and `depth` is greater than 0.
+#[cfg(feature = "rayon")]
+pub fn best_sequence<T, F, S>(fsm: &Fsm<T>,
+                              candidates: &[T::Msg],
+                              depth: usize,
+                              score: &F) -> Option<(Vec<T::Msg>, S)>
+    where T: FsmTypes,
+          T::Context: Clone + Send + Sync,
+          T::Msg: Clone + Send + Sync,
+          T::Output: Sync,
+          F: Fn(&Fsm<T>) -> S + Sync,
+          S: PartialOrd + Send
+{
+    if depth == 0 {
+        return Some((Vec::new(), score(fsm)));
+    }
+    candidates.par_iter().filter_map(|msg| {
+        let mut fork = fsm.fork();
+        fork.send(msg.clone());
+        best_sequence(&fork, candidates, depth - 1, score).map(|(mut rest, s)| {
+            rest.insert(0, msg.clone());
+            (rest, s)
+        })
+    }).max_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal))
+}
+
+/// The seed the default configs start their rng from
+pub const DEFAULT_SEED: u64 = 0x2545_f491_4f6c_dd1d;
+
+/// Tuning knobs for `mcts`
+#[derive(Debug, Clone)]
+pub struct MctsConfig {
+    /// The number of select/expand/rollout/backpropagate rounds to run
+    pub iterations: usize,
+    /// The maximum number of random messages sent during a rollout
+    pub rollout_depth: usize,
+    /// The UCT exploration constant
+    pub exploration: f64,
+    /// Seed for the rng choosing expansions and rollout messages, so searches are reproducible
+    pub seed: u64
+}
+
+impl Default for MctsConfig {
+    fn default() -> MctsConfig {
+        MctsConfig {
+            iterations: 1000,
+            rollout_depth: 10,
+            exploration: 2f64.sqrt(),
+            seed: DEFAULT_SEED
+        }
+    }
+}
+
+struct Node<T: FsmTypes> {
+    fsm: Fsm<T>,
+    msg: Option<T::Msg>,
+    parent: Option<usize>,
+    children: Vec<usize>,
+    untried: Vec<T::Msg>,
+    visits: u32,
+    total: f64
+}
+
+/// Run a Monte Carlo tree search using the fsm as the simulation model and return the best message
+/// to send next, or `None` if `actions` offers nothing from the current state.
+///
+/// `actions` returns the messages that may be sent to a machine in its current state, and
+/// `reward` scores the context reached at the end of each rollout. Higher rewards are better.
+pub fn mcts<T, A, R>(fsm: &Fsm<T>, actions: A, reward: R, config: &MctsConfig) -> Option<T::Msg>
+    where T: FsmTypes,
+          T::Context: Clone,
+          T::Msg: Clone,
+          A: Fn(&Fsm<T>) -> Vec<T::Msg>,
+          R: Fn(&T::Context) -> f64
+{
+    let mut rng = XorShift(config.seed.max(1));
+    let mut nodes = vec![Node {
+        fsm: fsm.fork(),
+        msg: None,
+        parent: None,
+        children: Vec::new(),
+        untried: actions(fsm),
+        visits: 0,
+        total: 0.0
+    }];
+
+    for _ in 0..config.iterations {
+        // Selection
+        let mut current = 0;
+        while nodes[current].untried.is_empty() && !nodes[current].children.is_empty() {
+            current = select_child(&nodes, current, config.exploration);
+        }
+
+        // Expansion
+        if !nodes[current].untried.is_empty() {
+            let i = rng.below(nodes[current].untried.len());
+            let msg = nodes[current].untried.swap_remove(i);
+            let mut child = nodes[current].fsm.fork();
+            child.send(msg.clone());
+            let untried = actions(&child);
+            nodes.push(Node {
+                fsm: child,
+                msg: Some(msg),
+                parent: Some(current),
+                children: Vec::new(),
+                untried,
+                visits: 0,
+                total: 0.0
+            });
+            let id = nodes.len() - 1;
+            nodes[current].children.push(id);
+            current = id;
+        }
+
+        // Rollout
+        let mut rollout = nodes[current].fsm.fork();
+        for _ in 0..config.rollout_depth {
+            let mut msgs = actions(&rollout);
+            if msgs.is_empty() {
+                break;
+            }
+            let i = rng.below(msgs.len());
+            rollout.send(msgs.swap_remove(i));
+        }
+        let value = reward(&rollout.ctx);
+
+        // Backpropagation
+        let mut node = Some(current);
+        while let Some(id) = node {
+            nodes[id].visits += 1;
+            nodes[id].total += value;
+            node = nodes[id].parent;
+        }
+    }
+
+    let best = nodes[0].children.iter().max_by_key(|&&id| nodes[id].visits).cloned();
+    best.and_then(|id| nodes[id].msg.clone())
+}
+
+/// Tuning knobs for the functions that send random streams of messages, `differential` and
+/// `Checker::check_random`
+#[derive(Debug, Clone)]
+pub struct RandomConfig {
+    /// The number of independent message streams to try
+    pub runs: usize,
+    /// The number of messages in each stream
+    pub length: usize,
+    /// Seed for the rng generating the streams, so a reported failure can be reproduced
+    pub seed: u64
+}
+
+impl Default for RandomConfig {
+    fn default() -> RandomConfig {
+        RandomConfig {
+            runs: 100,
+            length: 20,
+            seed: DEFAULT_SEED
+        }
+    }
+}
+
+/// The first step at which two machines fed the same messages behaved differently
+#[derive(Debug)]
+pub struct Difference<M, O> {
+    /// The stream sent to both machines, ending with the message they handled differently
+    pub msgs: Vec<M>,
+    /// The state each machine moved to and the output it produced for the last message
+    pub left: (&'static str, Outputs<O>),
+    pub right: (&'static str, Outputs<O>)
+}
+
+/// Feed random streams of messages drawn from `candidates` to forks of two versions of a machine,
+/// e.g. the original and a refactored one, and return the first difference in the states they
+/// reach or the output they produce. Outputs are compared by their `Debug` rendering. Returns
+/// `None` if no difference was found.
+pub fn differential<A, B>(left: &Fsm<A>,
+                          right: &Fsm<B>,
+                          candidates: &[A::Msg],
+                          config: &RandomConfig) -> Option<Difference<A::Msg, A::Output>>
+    where A: FsmTypes,
+          B: FsmTypes<Msg = A::Msg, Output = A::Output>,
+          A::Context: Clone,
+          B::Context: Clone,
+          A::Msg: Clone
+{
+    if candidates.is_empty() {
+        return None;
+    }
+    let mut rng = XorShift(config.seed.max(1));
+    for _ in 0..config.runs {
+        let mut l = left.fork();
+        let mut r = right.fork();
+        let mut msgs = Vec::with_capacity(config.length);
+        for _ in 0..config.length {
+            let msg = candidates[rng.below(candidates.len())].clone();
+            msgs.push(msg.clone());
+            let l_out = l.send(msg.clone());
+            let r_out = r.send(msg);
+            if l.state.0 != r.state.0 || format!("{:?}", l_out) != format!("{:?}", r_out) {
+                return Some(Difference {
+                    msgs,
+                    left: (l.state.0, l_out),
+                    right: (r.state.0, r_out)
+                });
+            }
+        }
+    }
+    None
+}
+
+fn select_child<T: FsmTypes>(nodes: &[Node<T>], parent: usize, exploration: f64) -> usize {
+    let ln_visits = f64::from(nodes[parent].visits).ln();
+    let uct = |id: usize| {
+        let visits = f64::from(nodes[id].visits);
+        nodes[id].total / visits + exploration * (ln_visits / visits).sqrt()
+    };
+    let children = &nodes[parent].children;
+    children.iter().cloned().max_by(|&a, &b| {
+        uct(a).partial_cmp(&uct(b)).unwrap_or(Ordering::Equal)
+    }).unwrap_or(children[0])
+}
+
+// A tiny xorshift rng. Rollouts only need cheap, reproducible randomness, not a dependency.
+pub(crate) struct XorShift(pub(crate) u64);
+
+impl XorShift {
+    pub(crate) fn below(&mut self, n: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % n as u64) as usize
+    }
+}
@@ -0,0 +1,45 @@
+//! Runtime statistics about which transitions an fsm actually takes
+
+use alloc::collections::BTreeMap;
+use alloc::collections::btree_map::Iter;
+use alloc::string::String;
+use core::fmt::Write;
+
+/// The number of times each `(from, to)` transition has been taken, including transitions from a
+/// state back to itself
+#[derive(Debug, Clone, Default)]
+pub struct TransitionCounts {
+    counts: BTreeMap<(&'static str, &'static str), u64>
+}
+
+impl TransitionCounts {
+    pub fn new() -> TransitionCounts {
+        TransitionCounts::default()
+    }
+
+    pub fn record(&mut self, from: &'static str, to: &'static str) {
+        *self.counts.entry((from, to)).or_insert(0) += 1;
+    }
+
+    pub fn get(&self, from: &'static str, to: &'static str) -> u64 {
+        self.counts.get(&(from, to)).cloned().unwrap_or(0)
+    }
+
+    pub fn iter(&self) -> Iter<'_, (&'static str, &'static str), u64> {
+        self.counts.iter()
+    }
+
+    /// Render the counts as a graphviz digraph. Each edge is labelled with its count and its width
+    /// is scaled relative to the most frequently taken transition, so hot paths stand out.
+    pub fn to_dot(&self) -> String {
+        let max = self.counts.values().cloned().max().unwrap_or(1) as f64;
+        let mut dot = String::from("digraph fsm {\n");
+        for (&(from, to), &count) in &self.counts {
+            let width = 1.0 + 4.0 * count as f64 / max;
+            writeln!(dot, "    \"{}\" -> \"{}\" [label=\"{}\", penwidth={:.1}];",
+                     from, to, count, width).unwrap();
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
@@ -0,0 +1,84 @@
+//! Table driven transition tests. See `transition_tests!`.
+
+/// Expand a table of transitions into one named test per row, along with a function that runs
+/// every row and returns the transitions they took as `TransitionCounts`. Comparing those counts
+/// with the transitions the machine can take shows which ones the table doesn't cover yet.
+///
+/// Each row gives the test name, the state to start in and its context, the message to send, the
+/// expected state, a predicate the resulting context must satisfy, and the expected output. Output
+/// is compared by its `Debug` rendering.
+///
+/// ```
+/// # #[macro_use]
+/// # extern crate funfsm;
+/// # use funfsm::{FsmTypes, Outputs, StateFn};
+/// # #[derive(Debug)]
+/// # pub enum Msg { Coin, Push }
+/// # #[derive(Debug)]
+/// # pub struct TurnstileTypes;
+/// # impl FsmTypes for TurnstileTypes {
+/// #     type Context = u32;
+/// #     type Msg = Msg;
+/// #     type Output = &'static str;
+/// # }
+/// # type Next = (StateFn<TurnstileTypes>, Outputs<&'static str>);
+/// # pub fn locked(coins: &mut u32, msg: Msg) -> Next {
+/// #     match msg {
+/// #         Msg::Coin => { *coins += 1; next!(unlocked, Outputs::One("unlock")) },
+/// #         Msg::Push => next!(locked)
+/// #     }
+/// # }
+/// # pub fn unlocked(_: &mut u32, msg: Msg) -> Next {
+/// #     match msg {
+/// #         Msg::Coin => next!(unlocked),
+/// #         Msg::Push => next!(locked)
+/// #     }
+/// # }
+/// transition_tests! {
+///     types TurnstileTypes;
+///     coverage turnstile_coverage;
+///     coin_unlocks: locked(0), Msg::Coin => unlocked, |coins: &u32| *coins == 1, ["unlock"];
+///     push_locks: unlocked(1), Msg::Push => locked, |coins: &u32| *coins == 1, [];
+/// }
+///
+/// # fn main() {
+/// let counts = turnstile_coverage();
+/// assert_eq!(counts.get("locked", "unlocked"), 1);
+/// assert_eq!(counts.get("unlocked", "locked"), 1);
+/// # }
+/// ```
+#[macro_export]
+macro_rules! transition_tests {
+    (
+        types $types:ty;
+        coverage $coverage:ident;
+        $(
+            $name:ident: $from:ident($ctx:expr), $msg:expr => $to:ident, $pred:expr,
+                [$( $output:expr ),*];
+        )*
+    ) => {
+        $(
+            #[test]
+            fn $name() {
+                let mut fsm = $crate::Fsm::<$types>::new($ctx,
+                                                         $crate::StateFn(stringify!($from), $from));
+                let output = fsm.send($msg);
+                assert_eq!(fsm.get_state().0, stringify!($to));
+                assert!(($pred)(&fsm.ctx), "Unexpected context: {}", stringify!($pred));
+                let expected: Vec<<$types as $crate::FsmTypes>::Output> = vec![$( $output ),*];
+                assert_eq!(format!("{:?}", output), format!("{:?}", expected));
+            }
+        )*
+
+        pub fn $coverage() -> $crate::stats::TransitionCounts {
+            let mut counts = $crate::stats::TransitionCounts::new();
+            $(
+                let mut fsm = $crate::Fsm::<$types>::new($ctx,
+                                                         $crate::StateFn(stringify!($from), $from));
+                fsm.send($msg);
+                counts.record(stringify!($from), fsm.get_state().0);
+            )*
+            counts
+        }
+    };
+}
@@ -0,0 +1,233 @@
+//! Timers that deliver messages to an fsm after a delay, or periodically.
+//!
+//! There is no runtime thread driving a local `Fsm`, so whoever owns the machine calls
+//! `Fsm::poll_timers` to deliver any messages that are due, e.g. from an event loop that sleeps
+//! until `Fsm::next_timer`. Time comes from an injectable `Clock`, so tests can use a `MockClock`
+//! and advance time deterministically.
+//!
+//! The owner schedules timers with `Fsm::send_after` and `Fsm::send_every`, and state functions
+//! request them through a `TimerRequest` queue in the context.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::time::Duration;
+#[cfg(feature = "std")]
+use std::sync::Mutex;
+#[cfg(feature = "std")]
+use std::time::Instant;
+use fsm::{FsmTypes, MsgFn};
+
+/// A source of monotonic time, measured from an arbitrary starting point
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Duration;
+}
+
+/// Real time, measured from when the clock was created
+#[cfg(feature = "std")]
+pub struct SystemClock {
+    start: Instant
+}
+
+#[cfg(feature = "std")]
+impl SystemClock {
+    pub fn new() -> SystemClock {
+        SystemClock {
+            start: Instant::now()
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Default for SystemClock {
+    fn default() -> SystemClock {
+        SystemClock::new()
+    }
+}
+
+#[cfg(feature = "std")]
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+/// A clock that only moves when told to. Clones share the same time, so a test can keep one
+/// handle and give another to the fsm.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Default)]
+pub struct MockClock {
+    now: Arc<Mutex<Duration>>
+}
+
+#[cfg(feature = "std")]
+impl MockClock {
+    pub fn new() -> MockClock {
+        MockClock::default()
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+}
+
+#[cfg(feature = "std")]
+impl Clock for MockClock {
+    fn now(&self) -> Duration {
+        *self.now.lock().unwrap()
+    }
+}
+
+// Without `std` there is no real time to default to, so time stands still until the owner sets a
+// clock
+#[cfg(not(feature = "std"))]
+struct StoppedClock;
+
+#[cfg(not(feature = "std"))]
+impl Clock for StoppedClock {
+    fn now(&self) -> Duration {
+        Duration::from_secs(0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TimerId(u64);
+
+/// How long a timer lives
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerScope {
+    /// Until it fires (or forever, for periodic timers) or is cancelled
+    Machine,
+    /// Cancelled as soon as the fsm transitions to a different state
+    State
+}
+
+/// A timer requested by a state function, which can't reach the fsm's timers directly. State
+/// functions push requests onto the queue in the context given to `Fsm::set_timer_queue`.
+#[derive(Debug, Clone)]
+pub enum TimerRequest<M> {
+    /// Deliver the message once the delay has elapsed
+    After(Duration, M, TimerScope),
+    /// Deliver the message built by the function every period
+    Every(Duration, fn() -> M, TimerScope)
+}
+
+enum TimerMsg<T: FsmTypes> {
+    Once(T::Msg),
+    Every(Duration, MsgFn<T>)
+}
+
+struct Timer<T: FsmTypes> {
+    id: TimerId,
+    due: Duration,
+    scope: TimerScope,
+    msg: TimerMsg<T>
+}
+
+// The pending timers of a single fsm
+pub(crate) struct Timers<T: FsmTypes> {
+    clock: Arc<dyn Clock>,
+    next_id: u64,
+    pending: Vec<Timer<T>>
+}
+
+impl<T: FsmTypes> Timers<T> {
+    pub fn new() -> Timers<T> {
+        #[cfg(feature = "std")]
+        let clock = Arc::new(SystemClock::new());
+        #[cfg(not(feature = "std"))]
+        let clock = Arc::new(StoppedClock);
+        Timers {
+            clock,
+            next_id: 0,
+            pending: Vec::new()
+        }
+    }
+
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    pub fn now(&self) -> Duration {
+        self.clock.now()
+    }
+
+    pub fn once(&mut self, delay: Duration, msg: T::Msg, scope: TimerScope) -> TimerId {
+        self.add(delay, TimerMsg::Once(msg), scope)
+    }
+
+    pub fn every(&mut self, period: Duration, msg: MsgFn<T>, scope: TimerScope) -> TimerId {
+        assert!(period > Duration::from_secs(0), "The timer period must be non-zero");
+        self.add(period, TimerMsg::Every(period, msg), scope)
+    }
+
+    fn add(&mut self, delay: Duration, msg: TimerMsg<T>, scope: TimerScope) -> TimerId {
+        let id = TimerId(self.next_id);
+        self.next_id += 1;
+        let due = self.clock.now() + delay;
+        self.pending.push(Timer { id, due, scope, msg });
+        id
+    }
+
+    pub fn cancel(&mut self, id: TimerId) -> bool {
+        let before = self.pending.len();
+        self.pending.retain(|t| t.id != id);
+        self.pending.len() != before
+    }
+
+    pub fn cancel_state_scoped(&mut self) {
+        self.pending.retain(|t| t.scope == TimerScope::Machine);
+    }
+
+    pub fn clear(&mut self) {
+        self.pending.clear();
+    }
+
+    /// The time until the earliest timer is due, zero if it is overdue
+    pub fn next_due(&self) -> Option<Duration> {
+        let now = self.clock.now();
+        self.pending.iter().map(|t| t.due).min().map(|due| {
+            if due > now { due - now } else { Duration::from_secs(0) }
+        })
+    }
+
+    /// Remove and return the message of the earliest timer that is due, rescheduling it if it is
+    /// periodic. Timers due at the same time fire in the order they were scheduled.
+    pub fn pop_due(&mut self) -> Option<T::Msg> {
+        let now = self.clock.now();
+        let i = self.pending.iter().enumerate()
+            .filter(|&(_, t)| t.due <= now)
+            .min_by_key(|&(_, t)| (t.due, t.id.0))
+            .map(|(i, _)| i)?;
+        let timer = self.pending.remove(i);
+        match timer.msg {
+            TimerMsg::Once(msg) => Some(msg),
+            TimerMsg::Every(period, f) => {
+                self.pending.push(Timer {
+                    id: timer.id,
+                    due: timer.due + period,
+                    scope: timer.scope,
+                    msg: TimerMsg::Every(period, f)
+                });
+                Some(f())
+            }
+        }
+    }
+}
+
+impl<T: FsmTypes> Clone for Timers<T> where T::Msg: Clone {
+    fn clone(&self) -> Timers<T> {
+        Timers {
+            clock: self.clock.clone(),
+            next_id: self.next_id,
+            pending: self.pending.iter().map(|t| Timer {
+                id: t.id,
+                due: t.due,
+                scope: t.scope,
+                msg: match t.msg {
+                    TimerMsg::Once(ref msg) => TimerMsg::Once(msg.clone()),
+                    TimerMsg::Every(period, f) => TimerMsg::Every(period, f)
+                }
+            }).collect()
+        }
+    }
+}
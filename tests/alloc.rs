@@ -0,0 +1,83 @@
+//! Sending a message that produces no output, or a single output, must not touch the allocator,
+//! since machines may run once per network packet. This lives in its own test binary because it
+//! replaces the global allocator.
+
+#[macro_use]
+extern crate funfsm;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use funfsm::{Fsm, StateFn, FsmTypes, Outputs};
+
+struct Counting;
+
+// Counted per thread, so tests running in parallel don't see each other's allocations
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+fn allocations() -> usize {
+    ALLOCATIONS.with(Cell::get)
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+#[derive(Debug)]
+pub struct PacketTypes;
+
+impl FsmTypes for PacketTypes {
+    type Context = u64;
+    type Msg = u8;
+    type Output = u8;
+}
+
+pub fn open(bytes: &mut u64, packet: u8) -> (StateFn<PacketTypes>, Outputs<u8>) {
+    *bytes += u64::from(packet);
+    if packet == 0 {
+        return next!(closed);
+    }
+    next!(open)
+}
+
+pub fn closed(_: &mut u64, _: u8) -> (StateFn<PacketTypes>, Outputs<u8>) {
+    next!(closed)
+}
+
+// Acknowledges every packet
+pub fn acking(_: &mut u64, packet: u8) -> (StateFn<PacketTypes>, Outputs<u8>) {
+    next!(acking, Outputs::One(packet))
+}
+
+#[test]
+fn test_no_output_does_not_allocate() {
+    let mut fsm = Fsm::<PacketTypes>::new(0, state_fn!(open));
+    let before = allocations();
+    for packet in 1..100 {
+        assert!(fsm.send(packet).is_empty());
+    }
+    fsm.send(0);
+    assert_eq!(allocations(), before);
+    assert_eq!(fsm.get_state(), ("closed", &4950));
+}
+
+#[test]
+fn test_one_output_does_not_allocate() {
+    let mut fsm = Fsm::<PacketTypes>::new(0, state_fn!(acking));
+    let before = allocations();
+    for packet in 1..100 {
+        assert_eq!(fsm.send(packet)[..], [packet]);
+    }
+    assert_eq!(allocations(), before);
+}
@@ -0,0 +1,128 @@
+//! A login machine that looks users up in a database before letting them in. The lookup is a
+//! future that isn't ready on its first poll, standing in for a real query.
+
+#[macro_use]
+extern crate funfsm;
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+use funfsm::{FsmTypes, Outputs};
+use funfsm::async_fsm::{AsyncFsm, AsyncStateFn, BoxFuture};
+
+#[derive(Debug, Default)]
+pub struct Context {
+    pub users: Vec<String>,
+    pub queries: u32
+}
+
+#[derive(Debug)]
+pub enum Msg {
+    Login(String),
+    Logout
+}
+
+#[derive(Debug)]
+pub struct LoginTypes;
+
+impl FsmTypes for LoginTypes {
+    type Context = Context;
+    type Msg = Msg;
+    type Output = String;
+}
+
+type Next = (AsyncStateFn<LoginTypes>, Outputs<String>);
+
+struct Lookup<'a> {
+    ctx: &'a mut Context,
+    user: String,
+    polled: bool
+}
+
+impl<'a> Future for Lookup<'a> {
+    type Output = Next;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut TaskContext) -> Poll<Next> {
+        if !self.polled {
+            self.polled = true;
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+        self.ctx.queries += 1;
+        if self.ctx.users.contains(&self.user) {
+            let welcome = format!("welcome {}", self.user);
+            Poll::Ready((async_state_fn!(logged_in), Outputs::One(welcome)))
+        } else {
+            Poll::Ready((async_state_fn!(idle), Outputs::One("denied".to_string())))
+        }
+    }
+}
+
+fn ready<'a>(next: Next) -> BoxFuture<'a, Next> {
+    Box::pin(std::future::ready(next))
+}
+
+pub fn idle(ctx: &mut Context, msg: Msg) -> BoxFuture<'_, Next> {
+    match msg {
+        Msg::Login(user) => Box::pin(Lookup { ctx, user, polled: false }),
+        Msg::Logout => ready((async_state_fn!(idle), Outputs::Empty))
+    }
+}
+
+pub fn logged_in(_: &mut Context, msg: Msg) -> BoxFuture<'_, Next> {
+    match msg {
+        Msg::Login(_) => ready((async_state_fn!(logged_in), Outputs::Empty)),
+        Msg::Logout => ready((async_state_fn!(idle), Outputs::One("bye".to_string())))
+    }
+}
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+fn block_on<F: Future>(fut: F) -> F::Output {
+    let mut fut = Box::pin(fut);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = TaskContext::from_waker(&waker);
+    loop {
+        if let Poll::Ready(output) = fut.as_mut().poll(&mut cx) {
+            return output;
+        }
+        thread::park();
+    }
+}
+
+fn new_fsm() -> AsyncFsm<LoginTypes> {
+    let ctx = Context { users: vec!["cat".to_string()], queries: 0 };
+    AsyncFsm::new(ctx, async_state_fn!(idle))
+}
+
+#[test]
+fn test_async_transitions() {
+    let mut fsm = new_fsm();
+    assert_eq!(block_on(fsm.send(Msg::Login("dog".to_string()))), vec!["denied"]);
+    assert_eq!(fsm.get_state().0, "idle");
+    assert_eq!(block_on(fsm.send(Msg::Login("cat".to_string()))), vec!["welcome cat"]);
+    assert_eq!(fsm.get_state().0, "logged_in");
+    assert_eq!(block_on(fsm.send(Msg::Logout)), vec!["bye"]);
+    assert_eq!(fsm.get_state().0, "idle");
+    assert_eq!(fsm.ctx.queries, 2);
+}
+
+#[test]
+fn test_dropped_send_keeps_state() {
+    let mut fsm = new_fsm();
+    {
+        let mut send = fsm.send(Msg::Login("cat".to_string()));
+        let mut cx = TaskContext::from_waker(Waker::noop());
+        assert!(Pin::new(&mut send).poll(&mut cx).is_pending());
+    }
+    assert_eq!(fsm.get_state().0, "idle");
+    assert_eq!(fsm.ctx.queries, 0);
+}
@@ -0,0 +1,94 @@
+//! A logger whose lines are shipped to a collector in batches
+
+#[macro_use]
+extern crate funfsm;
+
+use std::cell::RefCell;
+use std::sync::Arc;
+use std::time::Duration;
+use funfsm::{Fsm, StateFn, FsmTypes, Outputs};
+use funfsm::batch::Batcher;
+use funfsm::timer::{MockClock, TimerScope};
+
+#[derive(Debug)]
+pub enum LogMsg {
+    Line(u32),
+    Close
+}
+
+#[derive(Debug)]
+pub struct LogTypes;
+
+impl FsmTypes for LogTypes {
+    type Context = ();
+    type Msg = LogMsg;
+    type Output = u32;
+}
+
+pub fn logging(_: &mut (), msg: LogMsg) -> (StateFn<LogTypes>, Outputs<u32>) {
+    match msg {
+        LogMsg::Line(n) => next!(logging, vec![n]),
+        LogMsg::Close => done!()
+    }
+}
+
+#[test]
+fn test_batching() {
+    let batches = RefCell::new(Vec::new());
+    let clock = MockClock::new();
+    let mut fsm = Fsm::<LogTypes>::new((), state_fn!(logging));
+    fsm.set_clock(Arc::new(clock.clone()));
+    let mut batcher = Batcher::new(fsm, |batch| batches.borrow_mut().push(batch), 3,
+                                   Duration::from_secs(1));
+
+    // Flushed by size
+    for n in 1..5 {
+        batcher.send(LogMsg::Line(n));
+    }
+    assert_eq!(*batches.borrow(), vec![vec![1, 2, 3]]);
+    assert_eq!(batcher.pending_len(), 1);
+
+    // Flushed by age
+    clock.advance(Duration::from_millis(999));
+    batcher.poll();
+    assert_eq!(batcher.pending_len(), 1);
+    clock.advance(Duration::from_millis(1));
+    batcher.poll();
+    assert_eq!(batches.borrow().last(), Some(&vec![4]));
+
+    // Flushed when the machine finishes
+    batcher.send(LogMsg::Line(5));
+    batcher.send(LogMsg::Close);
+    assert_eq!(batches.borrow().last(), Some(&vec![5]));
+    assert_eq!(batches.borrow().len(), 3);
+}
+
+#[test]
+fn test_batch_timer_output() {
+    let batches = RefCell::new(Vec::new());
+    let clock = MockClock::new();
+    let mut fsm = Fsm::<LogTypes>::new((), state_fn!(logging));
+    fsm.set_clock(Arc::new(clock.clone()));
+    fsm.send_after(Duration::from_secs(1), LogMsg::Line(1), TimerScope::Machine);
+    let mut batcher = Batcher::new(fsm, |batch| batches.borrow_mut().push(batch), 3,
+                                   Duration::from_secs(1));
+
+    clock.advance(Duration::from_secs(1));
+    batcher.poll_timers();
+    assert_eq!(batcher.pending_len(), 1);
+    clock.advance(Duration::from_secs(1));
+    batcher.poll_timers();
+    assert_eq!(*batches.borrow(), vec![vec![1]]);
+}
+
+#[test]
+fn test_batch_buffered_output() {
+    let batches = RefCell::new(Vec::new());
+    let mut fsm = Fsm::<LogTypes>::new((), state_fn!(logging));
+    fsm.buffer_outputs();
+    let mut batcher = Batcher::new(fsm, |batch| batches.borrow_mut().push(batch), 2,
+                                   Duration::from_secs(1));
+    batcher.send(LogMsg::Line(1));
+    batcher.send(LogMsg::Line(2));
+    assert_eq!(*batches.borrow(), vec![vec![1, 2]]);
+}
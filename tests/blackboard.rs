@@ -0,0 +1,42 @@
+extern crate funfsm;
+
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::channel;
+use funfsm::blackboard::Blackboard;
+
+#[derive(Debug, PartialEq)]
+enum Msg {
+    BoardChanged(u64)
+}
+
+#[test]
+fn test_update_notifies_subscribers() {
+    let board = Blackboard::new(vec![1, 2, 3]);
+    let (tx, rx) = channel();
+    let tx = Mutex::new(tx);
+    board.subscribe(move |version| tx.lock().unwrap().send(Msg::BoardChanged(version)).unwrap());
+
+    let (version, before) = board.read();
+    assert_eq!(version, 0);
+    assert_eq!(board.update(|v| v.iter().map(|x| x * 2).collect()), 1);
+    assert_eq!(rx.try_recv(), Ok(Msg::BoardChanged(1)));
+
+    // Earlier readers keep the value they saw
+    assert_eq!(*before, vec![1, 2, 3]);
+    assert_eq!(*board.read().1, vec![2, 4, 6]);
+    assert_eq!(board.version(), 1);
+}
+
+#[test]
+fn test_subscriber_may_update() {
+    let board = Arc::new(Blackboard::new(0));
+    let inner = board.clone();
+    // A subscriber that reacts by updating the board again must not deadlock
+    board.subscribe(move |version| {
+        if version == 1 {
+            inner.update(|v| v + 10);
+        }
+    });
+    assert_eq!(board.update(|v| v + 1), 1);
+    assert_eq!(board.read(), (2, Arc::new(11)));
+}
@@ -2,7 +2,7 @@
 //! the cat food bowl. Our cat is very whiny and will always be fed when her bowl is empty and she
 //! meows. If there is already food in the bowl, she will have to eat it before we give her more.
 
-#![cfg_attr(feature = "cargo-clippy", allow(needless_pass_by_value))]
+#![allow(clippy::needless_pass_by_value)]
 
 #[macro_use]
 extern crate funfsm;
@@ -10,10 +10,11 @@ extern crate funfsm;
 #[macro_use]
 extern crate assert_matches;
 
-use funfsm::{Fsm, StateFn, FsmTypes};
-use funfsm::constraints::Constraints;
-use funfsm::constraints;
+use funfsm::{Fsm, StateFn, FsmTypes, Outputs};
+use funfsm::constraints::{ConstraintError, Constraints, Failure};
 use funfsm::fsm_check::Checker;
+use funfsm::search::RandomConfig;
+use funfsm::history::Metadata;
 
 const MAX_RESERVES: u8 = 10;
 const REFILL_THRESHOLD: u8 = 9;
@@ -35,6 +36,8 @@ impl Context {
     }
 }
 
+impl_display_state_via_debug!(Context);
+
 #[derive(Debug, Clone)]
 pub enum CatMsg {
     Meow,
@@ -66,7 +69,7 @@ impl FsmTypes for BowlTypes {
     type Output = StoreReq;
 }
 
-pub fn empty(ctx: &mut Context, msg: BowlMsg) -> (StateFn<BowlTypes>, Vec<StoreReq>) {
+pub fn empty(ctx: &mut Context, msg: BowlMsg) -> (StateFn<BowlTypes>, Outputs<StoreReq>) {
     if let BowlMsg::CatMsg(CatMsg::Meow) = msg {
         if ctx.reserves > 0 {
             // Fill the bowl
@@ -91,7 +94,7 @@ pub fn empty(ctx: &mut Context, msg: BowlMsg) -> (StateFn<BowlTypes>, Vec<StoreR
     next!(empty)
 }
 
-pub fn full(ctx: &mut Context, msg: BowlMsg) -> (StateFn<BowlTypes>, Vec<StoreReq>) {
+pub fn full(ctx: &mut Context, msg: BowlMsg) -> (StateFn<BowlTypes>, Outputs<StoreReq>) {
     if let BowlMsg::CatMsg(CatMsg::Eat(pct)) = msg {
         if pct >= ctx.contents {
             ctx.contents = 0;
@@ -143,6 +146,257 @@ fn test_state_transitions() {
     }
 }
 
+#[test]
+fn test_reset() {
+    let mut fsm = Fsm::<BowlTypes>::new(Context::new(), state_fn!(empty));
+    fsm.send(BowlMsg::CatMsg(CatMsg::Meow));
+    assert_eq!(fsm.get_state().0, "full");
+    fsm.reset(Context::new());
+    let (name, ctx) = fsm.get_state();
+    assert_eq!(name, "empty");
+    assert_eq!(ctx.reserves, MAX_RESERVES);
+}
+
+#[test]
+fn test_fork() {
+    let mut fsm = Fsm::<BowlTypes>::new(Context::new(), state_fn!(empty));
+    fsm.send(BowlMsg::CatMsg(CatMsg::Meow));
+    let mut fork = fsm.fork();
+    fork.send(BowlMsg::CatMsg(CatMsg::Eat(100)));
+    assert_eq!(fork.get_state().0, "empty");
+    let (name, ctx) = fsm.get_state();
+    assert_eq!(name, "full");
+    assert_eq!(ctx.contents, 100);
+
+    // Clones are forks, so a planner can snapshot a machine without naming `fork`
+    let snapshots = vec![fsm.clone(); 2];
+    assert!(snapshots.iter().all(|s| s.get_state().0 == "full"));
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn test_best_sequence() {
+    use funfsm::search::best_sequence;
+
+    let fsm = Fsm::<BowlTypes>::new(Context::new(), state_fn!(empty));
+    let candidates = vec![BowlMsg::CatMsg(CatMsg::Meow),
+                          BowlMsg::CatMsg(CatMsg::Eat(30)),
+                          BowlMsg::CatMsg(CatMsg::Eat(50))];
+    // Find the least full, but not empty, bowl
+    let score = |fsm: &Fsm<BowlTypes>| {
+        match fsm.get_state() {
+            ("full", ctx) => -(ctx.contents as i32),
+            _ => i32::MIN
+        }
+    };
+    let (msgs, score) = best_sequence(&fsm, &candidates, 2, &score).unwrap();
+    assert_eq!(score, -50);
+    assert_matches!(msgs[..], [BowlMsg::CatMsg(CatMsg::Meow), BowlMsg::CatMsg(CatMsg::Eat(50))]);
+}
+
+#[test]
+fn test_mcts() {
+    use funfsm::search::{mcts, MctsConfig};
+
+    let fsm = Fsm::<BowlTypes>::new(Context::new(), state_fn!(empty));
+    let actions = |_: &Fsm<BowlTypes>| vec![BowlMsg::CatMsg(CatMsg::Meow),
+                                            BowlMsg::CatMsg(CatMsg::Eat(100))];
+    let reward = |ctx: &Context| f64::from(ctx.contents);
+    let config = MctsConfig { iterations: 100, rollout_depth: 0, ..MctsConfig::default() };
+    assert_matches!(mcts(&fsm, actions, reward, &config), Some(BowlMsg::CatMsg(CatMsg::Meow)));
+}
+
+// A refactoring of `full` with an off by one error: the cat can't empty a bowl by eating exactly
+// as much as is in it
+pub fn full_v2(ctx: &mut Context, msg: BowlMsg) -> (StateFn<BowlTypes>, Outputs<StoreReq>) {
+    match msg {
+        BowlMsg::CatMsg(CatMsg::Eat(pct)) if pct > ctx.contents => {
+            ctx.contents = 0;
+            next!(empty)
+        },
+        BowlMsg::CatMsg(CatMsg::Eat(pct)) => {
+            ctx.contents -= pct;
+            (full_v2_state(), Outputs::Empty)
+        },
+        BowlMsg::StoreRpy(StoreRpy::Bowls(num)) => {
+            ctx.reserves += num;
+            (full_v2_state(), Outputs::Empty)
+        },
+        _ => (full_v2_state(), Outputs::Empty)
+    }
+}
+
+// The refactored state keeps its old name, so the two machines can be compared state for state
+fn full_v2_state() -> StateFn<BowlTypes> {
+    StateFn("full", full_v2)
+}
+
+#[test]
+fn test_differential() {
+    use funfsm::search::differential;
+
+    let ctx = Context { contents: 100, reserves: MAX_RESERVES };
+    let old = Fsm::<BowlTypes>::new(ctx.clone(), state_fn!(full));
+    let new = Fsm::<BowlTypes>::new(ctx, full_v2_state());
+    let config = RandomConfig::default();
+
+    // The versions agree until the cat eats exactly what is left
+    let candidates = [BowlMsg::CatMsg(CatMsg::Eat(30)), BowlMsg::StoreRpy(StoreRpy::Bowls(1))];
+    assert!(differential(&old, &new, &candidates, &config).is_none());
+
+    let candidates = [BowlMsg::CatMsg(CatMsg::Meow), BowlMsg::CatMsg(CatMsg::Eat(100))];
+    let diff = differential(&old, &new, &candidates, &config).unwrap();
+    assert_matches!(diff.msgs.last(), Some(&BowlMsg::CatMsg(CatMsg::Eat(100))));
+    assert_eq!(diff.left.0, "empty");
+    assert_eq!(diff.right.0, "full");
+}
+
+#[test]
+fn test_journal_replay() {
+    use funfsm::journal::MemoryJournal;
+
+    let journal = MemoryJournal::new();
+    let mut fsm = Fsm::<BowlTypes>::new(Context::new(), state_fn!(empty));
+    fsm.set_journal(journal.clone());
+    let mut metadata = Metadata::new();
+    metadata.insert("user".to_string(), "andrew".to_string());
+    fsm.send_with_metadata(BowlMsg::CatMsg(CatMsg::Meow), metadata.clone());
+    fsm.send(BowlMsg::CatMsg(CatMsg::Eat(100)));
+    fsm.send(BowlMsg::StoreRpy(StoreRpy::Bowls(3)));
+    let entries = journal.entries();
+    assert_eq!(entries.len(), 3);
+    assert_eq!(entries[1].state, "empty");
+    assert_eq!(entries[0].metadata, metadata);
+
+    let mut restored = Fsm::<BowlTypes>::new(Context::new(), state_fn!(empty));
+    restored.record_history(10);
+    assert_eq!(restored.replay(entries.clone()), Ok(()));
+    let history = restored.history().unwrap();
+    assert_eq!(history.iter().next().unwrap().metadata, metadata);
+    assert_eq!(restored.get_state().0, fsm.get_state().0);
+    assert_eq!(restored.ctx.reserves, fsm.ctx.reserves);
+
+    let mut tampered = entries;
+    tampered[0].digest ^= 1;
+    let mut restored = Fsm::<BowlTypes>::new(Context::new(), state_fn!(empty));
+    let err = restored.replay(tampered).unwrap_err();
+    assert_eq!(err, "Replay diverged at entry 0: output differs");
+}
+
+#[test]
+fn test_send_all() {
+    let mut fsm = Fsm::<BowlTypes>::new(Context::new(), state_fn!(empty));
+    let msgs = vec![BowlMsg::CatMsg(CatMsg::Meow),
+                    BowlMsg::CatMsg(CatMsg::Eat(100)),
+                    BowlMsg::CatMsg(CatMsg::Meow),
+                    BowlMsg::CatMsg(CatMsg::Eat(40))];
+    let (output, state) = fsm.send_all(msgs);
+    assert_eq!(output.len(), 2);
+    assert_eq!(state, "full");
+    assert_eq!(fsm.ctx.contents, 60);
+}
+
+#[test]
+fn test_buffered_outputs() {
+    let mut fsm = Fsm::builder(Context::new(), state_fn!(empty)).buffer_outputs().build();
+    assert!(fsm.send(BowlMsg::CatMsg(CatMsg::Meow)).is_empty());
+    assert_matches!(fsm.take_outputs()[..], [StoreReq::Buy(10)]);
+    fsm.send(BowlMsg::CatMsg(CatMsg::Eat(100)));
+    fsm.send(BowlMsg::CatMsg(CatMsg::Meow));
+    assert_eq!(fsm.drain_outputs().count(), 1);
+    assert!(fsm.take_outputs().is_empty());
+}
+
+#[test]
+fn test_transition_counts() {
+    let mut fsm = Fsm::<BowlTypes>::new(Context::new(), state_fn!(empty));
+    fsm.count_transitions();
+    fsm.send(BowlMsg::CatMsg(CatMsg::Meow));
+    fsm.send(BowlMsg::CatMsg(CatMsg::Eat(50)));
+    fsm.send(BowlMsg::CatMsg(CatMsg::Eat(50)));
+    let counts = fsm.transition_counts().unwrap();
+    assert_eq!(counts.get("empty", "full"), 1);
+    assert_eq!(counts.get("full", "full"), 1);
+    assert_eq!(counts.get("full", "empty"), 1);
+    assert_eq!(counts.get("empty", "empty"), 0);
+    assert!(counts.to_dot().contains("\"empty\" -> \"full\" [label=\"1\", penwidth=5.0];"));
+}
+
+#[test]
+fn test_history() {
+    let mut fsm = Fsm::<BowlTypes>::new(Context::new(), state_fn!(empty));
+    fsm.record_history(2);
+    fsm.send(BowlMsg::CatMsg(CatMsg::Meow));
+    fsm.send(BowlMsg::CatMsg(CatMsg::Eat(50)));
+    fsm.send(BowlMsg::CatMsg(CatMsg::Eat(50)));
+    let history = fsm.history().unwrap();
+    assert_eq!(history.len(), 2);
+    let entries: Vec<_> = history.iter().map(|t| (t.from, t.to, &t.msg[..])).collect();
+    assert_eq!(entries, vec![("full", "full", "CatMsg(Eat(50))"),
+                             ("full", "empty", "CatMsg(Eat(50))")]);
+    assert_eq!(history.entering("empty").len(), 1);
+    assert!(history.entering("full").iter().all(|t| t.from == "full"));
+}
+
+#[test]
+fn test_history_metadata() {
+    let mut fsm = Fsm::<BowlTypes>::new(Context::new(), state_fn!(empty));
+    fsm.record_history(10);
+    let mut metadata = Metadata::new();
+    metadata.insert("user".to_string(), "andrew".to_string());
+    fsm.send_with_metadata(BowlMsg::CatMsg(CatMsg::Meow), metadata);
+    fsm.send(BowlMsg::CatMsg(CatMsg::Eat(50)));
+    let history = fsm.history().unwrap();
+    let users: Vec<_> = history.iter().map(|t| t.metadata.get("user").cloned()).collect();
+    assert_eq!(users, vec![Some("andrew".to_string()), None]);
+}
+
+#[test]
+fn test_first_divergence() {
+    use funfsm::history::first_divergence;
+
+    let mut a = Fsm::<BowlTypes>::new(Context::new(), state_fn!(empty));
+    let mut b = Fsm::<BowlTypes>::new(Context::new(), state_fn!(empty));
+    a.record_history(10);
+    b.record_history(10);
+    for fsm in [&mut a, &mut b] {
+        fsm.send(BowlMsg::CatMsg(CatMsg::Meow));
+        fsm.send(BowlMsg::CatMsg(CatMsg::Eat(30)));
+    }
+    assert!(first_divergence(a.history().unwrap(), b.history().unwrap(), 1).is_none());
+
+    a.send(BowlMsg::CatMsg(CatMsg::Eat(70)));
+    b.send(BowlMsg::CatMsg(CatMsg::Eat(50)));
+    let divergence = first_divergence(a.history().unwrap(), b.history().unwrap(), 1).unwrap();
+    assert_eq!(divergence.index, 2);
+    assert_eq!(divergence.common.len(), 1);
+    assert_eq!(divergence.left.unwrap().to, "empty");
+    assert_eq!(divergence.right.unwrap().to, "full");
+    assert!(divergence.to_string().contains("1: full --CatMsg(Eat(30))--> full"));
+}
+
+#[test]
+fn test_entry_and_exit_hooks() {
+    fn buy_one(_: &mut Context) -> Outputs<StoreReq> {
+        Outputs::One(StoreReq::Buy(1))
+    }
+    fn spill(ctx: &mut Context) -> Outputs<StoreReq> {
+        ctx.contents -= 10;
+        Outputs::Empty
+    }
+
+    let mut fsm = Fsm::<BowlTypes>::new(Context { contents: 0, reserves: 0 }, state_fn!(empty));
+    fsm.on_entry("empty", buy_one);
+    fsm.on_entry("full", spill);
+    fsm.send(BowlMsg::StoreRpy(StoreRpy::Bowls(5)));
+    assert_eq!(fsm.get_state().1.contents, 90);
+
+    // Staying in the same state doesn't cross a boundary
+    assert!(fsm.send(BowlMsg::CatMsg(CatMsg::Eat(30))).is_empty());
+    assert_eq!(fsm.get_state().1.contents, 60);
+    assert_matches!(fsm.send(BowlMsg::CatMsg(CatMsg::Eat(60)))[..], [StoreReq::Buy(1)]);
+}
+
 #[test]
 fn test_check() {
     let msgs = vec![BowlMsg::CatMsg(CatMsg::Meow),
@@ -154,7 +408,315 @@ fn test_check() {
     check_constraints(msgs);
 }
 
+#[test]
+fn test_check_failure_shows_context() {
+    let mut c = Constraints::new();
+    invariant!(c, |ctx: &Context| ctx.contents == 0);
+    let mut checker = Checker::<BowlTypes>::new(Context::new(), state_fn!(empty), c);
+    let err = checker.check(BowlMsg::CatMsg(CatMsg::Meow)).unwrap_err();
+    assert_matches!(err.failure, Failure::Invariant { .. });
+    assert_eq!(err.context.as_ref().unwrap(), "Context { contents: 100, reserves: 9 }");
+    assert!(err.to_string().starts_with("Failed invariant"));
+    assert!(err.to_string().ends_with("Context: Context { contents: 100, reserves: 9 }"));
+}
+
+#[test]
+fn test_state_invariant() {
+    let mut c = Constraints::new();
+    state_invariant!(c, "empty", |ctx: &Context| ctx.reserves == MAX_RESERVES);
+    let mut checker = Checker::<BowlTypes>::new(Context::new(), state_fn!(empty), c);
+
+    // Buying food uses up reserves, but that's fine while the bowl is full
+    assert_matches!(checker.check(BowlMsg::CatMsg(CatMsg::Meow)), Ok(_));
+    assert_matches!(checker.check(BowlMsg::CatMsg(CatMsg::Eat(50))), Ok(_));
+    let err = checker.check(BowlMsg::CatMsg(CatMsg::Eat(50))).unwrap_err();
+    assert_matches!(err.failure, Failure::StateInvariant { state: "empty", .. });
+}
+
+fn eats_at_most(ctx: &Context, msg: &BowlMsg) -> bool {
+    match *msg {
+        BowlMsg::CatMsg(CatMsg::Eat(pct)) => pct <= ctx.contents,
+        _ => true
+    }
+}
+
+#[test]
+fn test_trace() {
+    let mut c = Constraints::new();
+    state_invariant!(c, "empty", |ctx: &Context| ctx.reserves == MAX_RESERVES);
+    let mut checker = Checker::<BowlTypes>::new(Context::new(), state_fn!(empty), c);
+    checker.record_trace();
+    assert_matches!(checker.check(BowlMsg::CatMsg(CatMsg::Meow)), Ok(_));
+    assert_matches!(checker.check(BowlMsg::CatMsg(CatMsg::Eat(50))), Ok(_));
+    let err = checker.check(BowlMsg::CatMsg(CatMsg::Eat(50))).unwrap_err();
+    assert_eq!(err.trace.len(), 3);
+    assert_eq!((err.trace[2].from, err.trace[2].to), ("full", "empty"));
+    assert_eq!(checker.trace().unwrap(), &err.trace[..]);
+
+    let report = format!("{:#}", err);
+    assert!(report.contains("\n  0: empty --CatMsg(Meow)--> full [Buy(10)] Context: Context"));
+    assert!(!err.to_string().contains('\n'));
+}
+
+#[test]
+fn test_message_conditions() {
+    let mut c = Constraints::new();
+    precondition!(c, "full", eats_at_most);
+    postcondition!(c, "empty", |ctx: &Context, msg: &BowlMsg| {
+        !matches!(*msg, BowlMsg::CatMsg(CatMsg::Meow)) || ctx.contents == 100
+    });
+    let mut checker = Checker::<BowlTypes>::new(Context::new(), state_fn!(empty), c);
+    assert_matches!(checker.check(BowlMsg::CatMsg(CatMsg::Meow)), Ok(_));
+    assert_matches!(checker.check(BowlMsg::CatMsg(CatMsg::Eat(30))), Ok(_));
+    let err = checker.check(BowlMsg::CatMsg(CatMsg::Eat(80))).unwrap_err();
+    assert_eq!(err.failure, Failure::Precondition { state: "full", expression: "eats_at_most" });
+}
+
+#[test]
+fn test_output_constraint() {
+    let mut c = Constraints::new();
+    output!(c, |output: &[StoreReq]| output.iter().all(|&StoreReq::Buy(n)| n <= MAX_RESERVES));
+    output!(c, |output: &[StoreReq]| output.len() <= 1);
+    let mut checker = Checker::<BowlTypes>::new(Context::new(), state_fn!(empty), c);
+    for _ in 0..3 {
+        assert_matches!(checker.check(BowlMsg::CatMsg(CatMsg::Meow)), Ok(_));
+        assert_matches!(checker.check(BowlMsg::CatMsg(CatMsg::Eat(100))), Ok(_));
+    }
+
+    let mut c = Constraints::new();
+    output!(c, |output: &[StoreReq]| output.is_empty());
+    let mut checker = Checker::<BowlTypes>::new(Context::new(), state_fn!(empty), c);
+    let err = checker.check(BowlMsg::CatMsg(CatMsg::Meow)).unwrap_err();
+    assert_matches!(err.failure, Failure::Output { .. });
+}
+
+// Constraints every test of the bowl shares
+fn bowl_invariants() -> Constraints<BowlTypes> {
+    let mut c = Constraints::new();
+    invariant!(c, |ctx: &Context| ctx.contents <= 100);
+    state_invariant!(c, "empty", |ctx: &Context| ctx.contents == 0);
+    c
+}
+
+#[test]
+fn test_merge() {
+    let mut c = bowl_invariants();
+    let mut transitions = Constraints::new();
+    transition!(transitions, "empty" => "full", empty_to_full);
+    transition!(transitions, "full" => "empty", full_to_empty);
+    c.merge(transitions);
+    let mut more = Constraints::new();
+    transition!(more, "empty" => "full", leaves_full);
+    c.merge(more);
+    assert_eq!(c.transitions[&("empty", "full")].len(), 2);
+
+    let mut checker = Checker::<BowlTypes>::new(Context::new(), state_fn!(empty), c);
+    for msg in [BowlMsg::CatMsg(CatMsg::Meow),
+                BowlMsg::CatMsg(CatMsg::Eat(100)),
+                BowlMsg::CatMsg(CatMsg::Meow)] {
+        assert_matches!(checker.check(msg), Ok(_));
+    }
+}
+
+#[test]
+fn test_string_errors() {
+    let mut c = Constraints::<BowlTypes, String>::default();
+    invariant!(c, |ctx: &Context| ctx.contents == 0);
+    let mut checker = Checker::new(Context::new(), state_fn!(empty), c);
+    let err: String = checker.check(BowlMsg::CatMsg(CatMsg::Meow)).unwrap_err();
+    assert!(err.starts_with("Failed invariant"));
+    assert!(err.ends_with("Context: Context { contents: 100, reserves: 9 }"));
+}
+
+#[test]
+fn test_require_declared_transitions() {
+    let mut c = Constraints::new();
+    transition!(c, "empty" => "full");
+    let mut checker = Checker::<BowlTypes>::new(Context::new(), state_fn!(empty), c);
+    checker.require_declared_transitions();
+    assert_matches!(checker.check(BowlMsg::CatMsg(CatMsg::Meow)), Ok(_));
+    assert_matches!(checker.check(BowlMsg::CatMsg(CatMsg::Eat(50))), Ok(_));
+    let err = checker.check(BowlMsg::CatMsg(CatMsg::Eat(50))).unwrap_err();
+    assert_eq!(err.failure, Failure::Undeclared { from: "full", to: "empty" });
+}
+
+#[allow(unused_must_use)]
+fn changes_contents(init_ctx: &Context,
+                    final_ctx: &Context,
+                    _msg: &BowlMsg,
+                    _output: &[StoreReq]) -> Result<(), ConstraintError>
+{
+    check!("Any transition", init_ctx.contents != final_ctx.contents);
+    Ok(())
+}
+
+#[allow(unused_must_use)]
+fn leaves_full(_init_ctx: &Context,
+               final_ctx: &Context,
+               _msg: &BowlMsg,
+               _output: &[StoreReq]) -> Result<(), ConstraintError>
+{
+    check!("Transition to full", final_ctx.contents == 100);
+    Ok(())
+}
+
+#[test]
+fn test_wildcard_transitions() {
+    let mut c = Constraints::new();
+    transition!(c, "*" => "*", changes_contents);
+    transition!(c, ["empty", "closed"] => "full", leaves_full);
+    let mut checker = Checker::<BowlTypes>::new(Context::new(), state_fn!(empty), c);
+    checker.require_declared_transitions();
+    assert_matches!(checker.check(BowlMsg::CatMsg(CatMsg::Meow)), Ok(_));
+    assert_matches!(checker.check(BowlMsg::CatMsg(CatMsg::Eat(50))), Ok(_));
+    assert_matches!(checker.check(BowlMsg::CatMsg(CatMsg::Eat(50))), Ok(_));
+
+    let mut c = Constraints::new();
+    transition!(c, ["empty", "closed"] => "full");
+    let mut checker = Checker::<BowlTypes>::new(Context::new(), state_fn!(empty), c);
+    checker.require_declared_transitions();
+    assert_matches!(checker.check(BowlMsg::CatMsg(CatMsg::Meow)), Ok(_));
+    let err = checker.check(BowlMsg::CatMsg(CatMsg::Eat(100))).unwrap_err();
+    assert_eq!(err.failure, Failure::Undeclared { from: "full", to: "empty" });
+}
+
+#[test]
+fn test_forbid_transition() {
+    let mut c = Constraints::new();
+    forbid_transition!(c, "empty" => "empty");
+    let mut checker = Checker::<BowlTypes>::new(Context::new(), state_fn!(empty), c);
+    assert_matches!(checker.check(BowlMsg::CatMsg(CatMsg::Meow)), Ok(_));
+    assert_matches!(checker.check(BowlMsg::CatMsg(CatMsg::Eat(100))), Ok(_));
+
+    // Eating from an empty bowl leaves it empty
+    let err = checker.check(BowlMsg::CatMsg(CatMsg::Eat(10))).unwrap_err();
+    assert_eq!(err.failure, Failure::Forbidden { from: "empty", to: "empty" });
+    assert!(err.to_string().starts_with("Forbidden transition from empty to empty"));
+}
+
+fn bad_refill(init_ctx: &Context,
+              final_ctx: &Context,
+              _msg: &BowlMsg,
+              output: &[StoreReq]) -> Result<(), ConstraintError>
+{
+    check_all!("Transition from empty to full", [
+        init_ctx.contents == 0,
+        final_ctx.contents == 50,
+        output.is_empty(),
+        final_ctx.reserves < MAX_RESERVES
+    ]);
+    Ok(())
+}
+
+#[test]
+fn test_check_all() {
+    let mut c = Constraints::new();
+    transition!(c, "empty" => "full", bad_refill);
+    let mut checker = Checker::<BowlTypes>::new(Context::new(), state_fn!(empty), c);
+    let err = checker.check(BowlMsg::CatMsg(CatMsg::Meow)).unwrap_err();
+    let expressions: Vec<_> = match err.failure {
+        Failure::Checks(ref failures) => failures.iter().map(|f| match *f {
+            Failure::Check { expression, .. } => expression,
+            _ => panic!("Unexpected failure {:?}", f)
+        }).collect(),
+        _ => panic!("Unexpected failure {:?}", err.failure)
+    };
+    assert_eq!(expressions, ["final_ctx.contents == 50", "output.is_empty()"]);
+}
+
+#[test]
+fn test_violation_handler() {
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use funfsm::fsm::FailurePolicy;
+
+    let mut c = Constraints::new();
+    precondition!(c, "full", |ctx: &Context| ctx.contents == 100);
+    let mut checker = Checker::<BowlTypes>::new(Context::new(), state_fn!(empty), c);
+    let violations = Rc::new(Cell::new(0));
+    let count = violations.clone();
+    checker.set_violation_handler(move |_: &ConstraintError, fsm: &Fsm<BowlTypes>| {
+        count.set(count.get() + 1);
+        if fsm.get_state().1.contents > 50 {
+            FailurePolicy::Lenient
+        } else {
+            FailurePolicy::ErrorState(state_fn!(empty))
+        }
+    });
+    for msg in [BowlMsg::CatMsg(CatMsg::Meow),
+                BowlMsg::CatMsg(CatMsg::Eat(30)),
+                BowlMsg::CatMsg(CatMsg::Eat(30)),
+                BowlMsg::CatMsg(CatMsg::Eat(30))] {
+        assert_matches!(checker.check(msg), Ok(_));
+    }
+    // The second Eat continued with 70% left, the third was dropped with 40% left
+    assert_eq!(violations.get(), 2);
+    let (name, ctx) = checker.fsm.get_state();
+    assert_eq!(name, "empty");
+    assert_eq!(ctx.contents, 40);
+}
+
+#[test]
+#[should_panic(expected = "Failed invariant")]
+fn test_strict_failure_policy() {
+    use funfsm::fsm::FailurePolicy;
+
+    let mut c = Constraints::new();
+    invariant!(c, |ctx: &Context| ctx.contents == 0);
+    let mut checker = Checker::<BowlTypes>::new(Context::new(), state_fn!(empty), c);
+    checker.set_violation_handler(FailurePolicy::Strict);
+    let _ = checker.check(BowlMsg::CatMsg(CatMsg::Meow));
+}
+
+#[test]
+fn test_halt_failure_policy() {
+    use funfsm::fsm::FailurePolicy;
+
+    let mut c = Constraints::new();
+    invariant!(c, |ctx: &Context| ctx.contents == 0);
+    let mut checker = Checker::<BowlTypes>::new(Context::new(), state_fn!(empty), c);
+    checker.set_violation_handler(FailurePolicy::Halt);
+    assert_matches!(checker.check(BowlMsg::CatMsg(CatMsg::Meow)), Ok(_));
+    assert!(checker.fsm.is_finished());
+}
+
+#[test]
+fn test_check_random() {
+    use funfsm::journal::MemoryJournal;
+
+    let candidates = [BowlMsg::CatMsg(CatMsg::Meow),
+                      BowlMsg::CatMsg(CatMsg::Eat(30)),
+                      BowlMsg::CatMsg(CatMsg::Eat(70)),
+                      BowlMsg::CatMsg(CatMsg::Eat(100))];
+    let journal = MemoryJournal::new();
+    let mut checker = bowl_checker();
+    checker.fsm.set_journal(journal.clone());
+    assert_matches!(checker.check_random(&candidates, &RandomConfig::default()), Ok(()));
+    assert_eq!(checker.fsm.get_state().0, "empty");
+    // The random runs went to forks, and the machine kept its journal
+    assert!(journal.entries().is_empty());
+    assert_matches!(checker.check(BowlMsg::CatMsg(CatMsg::Meow)), Ok(_));
+    assert_eq!(journal.entries().len(), 1);
+
+    // Running low on food takes three refills, however the cat eats
+    let mut c = Constraints::new();
+    invariant!(c, |ctx: &Context| ctx.reserves >= MAX_RESERVES - 2);
+    let mut checker = Checker::<BowlTypes>::new(Context::new(), state_fn!(empty), c);
+    let failure = checker.check_random(&candidates, &RandomConfig::default()).unwrap_err();
+    let meows = failure.msgs.iter().filter(|msg| matches!(**msg, BowlMsg::CatMsg(CatMsg::Meow)));
+    assert_eq!(meows.count(), 3);
+    assert_matches!(failure.error.failure, Failure::Invariant { .. });
+    assert_eq!(checker.fsm.get_state().1.reserves, MAX_RESERVES - 3);
+}
+
 fn check_constraints(msgs: Vec<BowlMsg>) {
+    let mut checker = bowl_checker();
+    for msg in msgs {
+        assert_matches!(checker.check(msg), Ok(_));
+    }
+}
+
+fn bowl_checker() -> Checker<BowlTypes> {
     let mut c = Constraints::new();
     precondition!(c, "empty", |ctx: &Context| ctx.contents == 0);
     precondition!(c, "full", |ctx: &Context| ctx.contents > 0 && ctx.contents <= 100);
@@ -164,25 +726,19 @@ fn check_constraints(msgs: Vec<BowlMsg>) {
     transition!(c, "empty" => "full", empty_to_full);
     transition!(c, "full" => "empty", full_to_empty);
 
-    let mut checker = Checker::<BowlTypes>::new(Context::new(), state_fn!(empty), c);
-    for msg in msgs {
-        assert_matches!(checker.check(msg), Ok(_));
-    }
+    Checker::<BowlTypes>::new(Context::new(), state_fn!(empty), c)
 }
 
 #[allow(unused_must_use)]
 fn empty_to_full(init_ctx: &Context,
                  final_ctx: &Context,
                  msg: &BowlMsg,
-                 _output: &[StoreReq]) -> Result<(), String>
+                 _output: &[StoreReq]) -> Result<(), ConstraintError>
 {
    let s = "Transition from empty to full";
    check!(s, init_ctx.contents == 0);
    check!(s, final_ctx.contents == 100);
-   check!(s, match *msg {
-       BowlMsg::StoreRpy(_) | BowlMsg::CatMsg(CatMsg::Meow) => true,
-       _ => false
-   });
+   check!(s, matches!(*msg, BowlMsg::StoreRpy(_) | BowlMsg::CatMsg(CatMsg::Meow)));
    Ok(())
 }
 
@@ -190,15 +746,11 @@ fn empty_to_full(init_ctx: &Context,
 fn full_to_empty(init_ctx: &Context,
                  final_ctx: &Context,
                  msg: &BowlMsg,
-                 _output: &[StoreReq]) -> Result<(), String>
+                 _output: &[StoreReq]) -> Result<(), ConstraintError>
 {
     let s = "Transition from full to empty";
     check!(s, init_ctx.contents > 0);
     check!(s, final_ctx.contents == 0);
-    check!(s, { if let BowlMsg::CatMsg(CatMsg::Eat(_)) = *msg {
-        true
-    } else {
-        false
-    }});
+    check!(s, matches!(*msg, BowlMsg::CatMsg(CatMsg::Eat(_))));
    Ok(())
 }
@@ -0,0 +1,534 @@
+//! A connection protocol. Every state shares the handling of `Shutdown` and `Ping` through a
+//! common `session` parent, and requests that arrive while connecting are deferred until the
+//! connection is up.
+
+#[macro_use]
+extern crate funfsm;
+
+use std::any::Any;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use funfsm::{Fsm, StateFn, FsmTypes, DisplayState, Outputs};
+use funfsm::constraints::{ConstraintError, Constraints, Failure};
+use funfsm::fsm::FailurePolicy;
+use funfsm::fsm_check::{Checker, Step};
+use funfsm::timer::{Clock, MockClock, TimerRequest, TimerScope};
+
+#[derive(Debug, Clone, Default)]
+pub struct Context {
+    pub pings: u32,
+    pub clock: MockClock,
+    pub internal: Vec<Msg>,
+    pub timers: Vec<TimerRequest<Msg>>,
+    pub sockets: Arc<AtomicUsize>
+}
+
+impl DisplayState for Context {
+    fn display_state(&self) -> String {
+        format!("pings: {}", self.pings)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Msg {
+    Connected,
+    Request(u32),
+    Ping,
+    Probe,
+    Echo,
+    Remind,
+    Work(u64),
+    Pause,
+    Resume,
+    Abort,
+    Shutdown
+}
+
+#[derive(Debug)]
+pub struct ConnTypes;
+
+impl FsmTypes for ConnTypes {
+    type Context = Context;
+    type Msg = Msg;
+    type Output = String;
+}
+
+pub fn session(ctx: &mut Context, msg: Msg) -> (StateFn<ConnTypes>, Outputs<String>) {
+    match msg {
+        Msg::Shutdown => next!(closed, vec!["bye".to_string()]),
+        Msg::Pause => next!(paused),
+        Msg::Abort => done!(vec!["aborted".to_string()]),
+        Msg::Probe => {
+            ctx.internal.push(Msg::Ping);
+            next!(session, vec!["probe".to_string()])
+        },
+        // Feeds itself forever
+        Msg::Echo => {
+            ctx.internal.push(Msg::Echo);
+            next!(session, vec!["echo".to_string()])
+        },
+        Msg::Ping => {
+            ctx.pings += 1;
+            next!(session, vec!["pong".to_string()])
+        },
+        _ => next!(session)
+    }
+}
+
+pub fn connecting(ctx: &mut Context, msg: Msg) -> (StateFn<ConnTypes>, Outputs<String>) {
+    match msg {
+        Msg::Connected => next!(connected),
+        msg => parent!(session, connecting, ctx, msg)
+    }
+}
+
+pub fn connected(ctx: &mut Context, msg: Msg) -> (StateFn<ConnTypes>, Outputs<String>) {
+    match msg {
+        Msg::Request(id) => next!(connected, vec![format!("response {}", id)]),
+        // Ping ourselves later, unless the connection goes away first
+        Msg::Remind => {
+            let delay = Duration::from_secs(5);
+            ctx.timers.push(TimerRequest::After(delay, Msg::Ping, TimerScope::State));
+            next!(connected)
+        },
+        Msg::Work(ms) => {
+            ctx.clock.advance(Duration::from_millis(ms));
+            next!(connected)
+        },
+        msg => parent!(session, connected, ctx, msg)
+    }
+}
+
+pub fn paused(ctx: &mut Context, msg: Msg) -> (StateFn<ConnTypes>, Outputs<String>) {
+    match msg {
+        Msg::Resume => previous!(),
+        msg => parent!(session, paused, ctx, msg)
+    }
+}
+
+pub fn closed(_: &mut Context, _: Msg) -> (StateFn<ConnTypes>, Outputs<String>) {
+    next!(closed)
+}
+
+// Come up already connected if a socket was handed over, otherwise say hello and wait
+fn init(ctx: &mut Context) -> (StateFn<ConnTypes>, Outputs<String>) {
+    if ctx.sockets.load(Ordering::SeqCst) > 0 {
+        next!(connected)
+    } else {
+        next!(connecting, vec!["hello".to_string()])
+    }
+}
+
+fn is_request(msg: &Msg) -> bool {
+    matches!(*msg, Msg::Request(_))
+}
+
+fn new_fsm() -> Fsm<ConnTypes> {
+    Fsm::builder(Context::default(), state_fn!(connecting))
+        .defer("connecting", is_request)
+        .build()
+}
+
+#[test]
+fn test_parent_fallback() {
+    let mut fsm = new_fsm();
+    assert_eq!(fsm.send(Msg::Ping), vec!["pong"]);
+    assert_eq!(fsm.get_state().0, "connecting");
+    fsm.send(Msg::Connected);
+    assert_eq!(fsm.send(Msg::Ping), vec!["pong"]);
+    assert_eq!(fsm.get_state().0, "connected");
+    assert_eq!(fsm.get_state().1.pings, 2);
+    assert_eq!(fsm.send(Msg::Shutdown), vec!["bye"]);
+    assert_eq!(fsm.get_state().0, "closed");
+}
+
+#[test]
+fn test_resume_previous_state() {
+    let mut fsm = new_fsm();
+    fsm.send(Msg::Resume);
+    assert_eq!(fsm.get_state().0, "connecting");
+    fsm.send(Msg::Pause);
+    fsm.send(Msg::Resume);
+    assert_eq!(fsm.get_state().0, "connecting");
+    fsm.send(Msg::Connected);
+    fsm.send(Msg::Pause);
+    fsm.send(Msg::Pause);
+    assert_eq!(fsm.get_state().0, "paused");
+    fsm.send(Msg::Resume);
+    assert_eq!(fsm.get_state().0, "connected");
+}
+
+#[test]
+fn test_deferred_requests_replay_on_connect() {
+    let mut fsm = new_fsm();
+    assert!(fsm.send(Msg::Request(1)).is_empty());
+    assert!(fsm.send(Msg::Request(2)).is_empty());
+    assert_eq!(fsm.deferred_len(), 2);
+
+    // Messages that aren't deferred are still handled while connecting
+    assert_eq!(fsm.send(Msg::Ping), vec!["pong"]);
+
+    assert_eq!(fsm.send(Msg::Connected), vec!["response 1", "response 2"]);
+    assert_eq!(fsm.deferred_len(), 0);
+    assert_eq!(fsm.send(Msg::Request(3)), vec!["response 3"]);
+}
+
+#[test]
+fn test_timers() {
+    let clock = MockClock::new();
+    let mut fsm = new_fsm();
+    fsm.set_clock(Arc::new(clock.clone()));
+    fsm.send_after(Duration::from_secs(5), Msg::Connected, TimerScope::Machine);
+    fsm.send_every(Duration::from_secs(2), || Msg::Ping, TimerScope::State);
+    assert_eq!(fsm.next_timer(), Some(Duration::from_secs(2)));
+
+    clock.advance(Duration::from_secs(4));
+    assert_eq!(fsm.poll_timers(), vec!["pong", "pong"]);
+    assert_eq!(fsm.get_state().0, "connecting");
+    assert_eq!(fsm.next_timer(), Some(Duration::from_secs(1)));
+
+    // Connecting cancels the state scoped ping before it fires again at 6s
+    clock.advance(Duration::from_secs(3));
+    assert!(fsm.poll_timers().is_empty());
+    assert_eq!(fsm.get_state().0, "connected");
+    assert_eq!(fsm.get_state().1.pings, 2);
+    assert_eq!(fsm.next_timer(), None);
+
+    let id = fsm.send_after(Duration::from_secs(1), Msg::Shutdown, TimerScope::Machine);
+    assert!(fsm.cancel_timer(id));
+    clock.advance(Duration::from_secs(1));
+    assert!(fsm.poll_timers().is_empty());
+    assert_eq!(fsm.get_state().0, "connected");
+}
+
+#[test]
+fn test_state_timeout() {
+    let clock = MockClock::new();
+    let mut fsm = new_fsm();
+    fsm.set_clock(Arc::new(clock.clone()));
+    fsm.set_state_timeout("connecting", Duration::from_secs(5), || Msg::Shutdown);
+    clock.advance(Duration::from_secs(3));
+    assert!(fsm.poll_timers().is_empty());
+
+    // Any message restarts the window
+    fsm.send(Msg::Ping);
+    clock.advance(Duration::from_secs(3));
+    assert!(fsm.poll_timers().is_empty());
+    clock.advance(Duration::from_secs(2));
+    assert_eq!(fsm.poll_timers(), vec!["bye"]);
+    assert_eq!(fsm.get_state().0, "closed");
+
+    // Leaving the state cancels the timeout
+    fsm.reset(Context::default());
+    fsm.send(Msg::Connected);
+    assert_eq!(fsm.next_timer(), None);
+}
+
+#[test]
+fn test_timer_requests() {
+    let clock = MockClock::new();
+    let mut fsm = Fsm::builder(Context::default(), state_fn!(connecting))
+        .clock(Arc::new(clock.clone()))
+        .timer_queue(|ctx| &mut ctx.timers)
+        .build();
+    fsm.send(Msg::Connected);
+    fsm.send(Msg::Remind);
+    assert!(fsm.ctx.timers.is_empty());
+    clock.advance(Duration::from_secs(5));
+    assert_eq!(fsm.poll_timers(), vec!["pong"]);
+
+    // The reminder is scoped to `connected`
+    fsm.send(Msg::Remind);
+    fsm.send(Msg::Pause);
+    clock.advance(Duration::from_secs(5));
+    assert!(fsm.poll_timers().is_empty());
+}
+
+#[test]
+fn test_history_uses_clock() {
+    let clock = MockClock::new();
+    let mut fsm = new_fsm();
+    fsm.set_clock(Arc::new(clock.clone()));
+    fsm.record_history(10);
+    fsm.send(Msg::Ping);
+    clock.advance(Duration::from_secs(2));
+    fsm.send(Msg::Connected);
+    let history = fsm.history().unwrap();
+    let times: Vec<_> = history.iter().map(|t| t.at).collect();
+    assert_eq!(times, vec![Duration::from_secs(0), Duration::from_secs(2)]);
+    let recent: Vec<_> = history.since(Duration::from_secs(1)).iter().map(|t| t.to).collect();
+    assert_eq!(recent, vec!["connected"]);
+}
+
+#[test]
+fn test_deadline() {
+    let mut fsm = new_fsm();
+    fsm.set_clock(Arc::new(fsm.ctx.clock.clone()));
+    fsm.set_deadline(Duration::from_millis(10));
+    fsm.send(Msg::Connected);
+
+    // Overruns are ignored by default
+    fsm.send(Msg::Work(11));
+    assert_eq!(fsm.get_state().0, "connected");
+
+    fsm.set_failure_policy(FailurePolicy::ErrorState(state_fn!(closed)));
+    fsm.send(Msg::Work(10));
+    assert_eq!(fsm.get_state().0, "connected");
+    fsm.send(Msg::Work(11));
+    assert_eq!(fsm.get_state().0, "closed");
+}
+
+#[test]
+#[should_panic(expected = "State connected took 11ms, over the deadline of 10ms")]
+fn test_strict_deadline() {
+    let mut fsm = Fsm::builder(Context::default(), state_fn!(connecting))
+        .deadline(Duration::from_millis(10))
+        .failure_policy(FailurePolicy::Strict)
+        .build();
+    fsm.set_clock(Arc::new(fsm.ctx.clock.clone()));
+    fsm.send(Msg::Connected);
+    fsm.send(Msg::Work(11));
+}
+
+#[test]
+fn test_finished() {
+    let mut fsm = Fsm::builder(Context::default(), state_fn!(connecting))
+        .mark_final("closed")
+        .build();
+    fsm.send(Msg::Connected);
+    assert!(!fsm.is_finished());
+    fsm.send(Msg::Shutdown);
+    assert!(fsm.is_finished());
+
+    let mut fsm = new_fsm();
+    assert_eq!(fsm.send(Msg::Abort), vec!["aborted"]);
+    assert!(fsm.is_finished());
+    assert!(fsm.send(Msg::Ping).is_empty());
+    assert_eq!(fsm.ctx.pings, 0);
+}
+
+// Connecting must give up after 5 seconds
+fn timely_checker(timeout: bool) -> Checker<ConnTypes> {
+    let clock = MockClock::new();
+    let mut c = Constraints::new();
+    let now = clock.clone();
+    precondition!(c, "connecting", move |_: &Context| now.now() <= Duration::from_secs(5));
+    let mut checker = Checker::new(Context::default(), state_fn!(connecting), c);
+    checker.set_clock(clock);
+    if timeout {
+        checker.fsm.set_state_timeout("connecting", Duration::from_secs(5), || Msg::Shutdown);
+    }
+    checker
+}
+
+#[test]
+fn test_check_timers() {
+    let steps = vec![Step::Advance(Duration::from_secs(3)),
+                     Step::Advance(Duration::from_secs(4)),
+                     Step::Advance(Duration::from_secs(3)),
+                     Step::Send(Msg::Ping)];
+
+    let mut checker = timely_checker(true);
+    assert_eq!(checker.check_steps(steps.clone()), Ok(Outputs::One("bye".to_string())));
+    assert_eq!(checker.fsm.get_state().0, "closed");
+
+    let mut checker = timely_checker(false);
+    let err = checker.check_steps(steps).unwrap_err();
+    assert!(matches!(err.failure, Failure::Precondition { state: "connecting", .. }));
+}
+
+#[test]
+fn test_violation_goto() {
+    let mut c = Constraints::new();
+    precondition!(c, "connecting", |ctx: &Context| ctx.pings == 0);
+    let mut checker = Checker::new(Context::default(), state_fn!(connecting), c);
+    checker.set_clock(MockClock::new());
+    checker.fsm.on_exit("connecting", say_goodbye);
+    checker.fsm.send_every(Duration::from_secs(2), || Msg::Ping, TimerScope::State);
+    checker.set_violation_handler(|_: &ConstraintError, _: &Fsm<ConnTypes>| {
+        FailurePolicy::ErrorState(state_fn!(closed))
+    });
+    assert_eq!(checker.check(Msg::Ping), Ok(Outputs::One("pong".to_string())));
+
+    // The second ping fails the precondition, and the fsm leaves `connecting` like any transition
+    assert_eq!(checker.check(Msg::Ping), Ok(Outputs::One("goodbye".to_string())));
+    assert_eq!(checker.fsm.get_state().0, "closed");
+    assert_eq!(checker.fsm.next_timer(), None);
+}
+
+#[test]
+fn test_internal_messages() {
+    let mut fsm = new_fsm();
+    fsm.set_internal_queue(|ctx| &mut ctx.internal, 10);
+    assert_eq!(fsm.send(Msg::Probe), vec!["probe", "pong"]);
+    assert_eq!(fsm.ctx.pings, 1);
+    assert!(fsm.ctx.internal.is_empty());
+}
+
+#[test]
+#[should_panic(expected = "State connecting queued more than 10 internal messages for one message")]
+fn test_internal_message_limit_strict() {
+    let mut fsm = Fsm::builder(Context::default(), state_fn!(connecting))
+        .internal_queue(|ctx| &mut ctx.internal, 10)
+        .failure_policy(FailurePolicy::Strict)
+        .build();
+    fsm.send(Msg::Echo);
+}
+
+#[test]
+fn test_internal_message_limit() {
+    // Lenient by default: the messages past the limit are dropped
+    let mut fsm = Fsm::builder(Context::default(), state_fn!(connecting))
+        .internal_queue(|ctx| &mut ctx.internal, 10)
+        .build();
+    assert_eq!(fsm.send(Msg::Echo).len(), 11);
+    assert_eq!(fsm.get_state().0, "connecting");
+    assert!(fsm.ctx.internal.is_empty());
+
+    fsm.set_failure_policy(FailurePolicy::ErrorState(state_fn!(closed)));
+    assert_eq!(fsm.send(Msg::Echo).len(), 11);
+    assert_eq!(fsm.get_state().0, "closed");
+    assert!(fsm.ctx.internal.is_empty());
+
+    fsm.reset(Context::default());
+    fsm.set_failure_policy(FailurePolicy::Halt);
+    assert_eq!(fsm.send(Msg::Echo).len(), 11);
+    assert!(fsm.is_finished());
+}
+
+struct Socket(Arc<AtomicUsize>);
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn open_socket(ctx: &mut Context) -> Box<dyn Any + Send + Sync> {
+    ctx.sockets.fetch_add(1, Ordering::SeqCst);
+    Box::new(Socket(ctx.sockets.clone()))
+}
+
+#[test]
+fn test_entry_guard() {
+    let mut fsm = new_fsm();
+    let sockets = fsm.ctx.sockets.clone();
+    fsm.on_entry_guard("connected", open_socket);
+    fsm.send(Msg::Connected);
+    fsm.send(Msg::Ping);
+    assert_eq!(sockets.load(Ordering::SeqCst), 1);
+    fsm.send(Msg::Shutdown);
+    assert_eq!(sockets.load(Ordering::SeqCst), 0);
+
+    fsm.reset(Context { sockets: sockets.clone(), ..Context::default() });
+    fsm.send(Msg::Connected);
+    let fork = fsm.fork();
+    assert_eq!(sockets.load(Ordering::SeqCst), 1);
+    drop(fork);
+    assert_eq!(sockets.load(Ordering::SeqCst), 1);
+    drop(fsm);
+    assert_eq!(sockets.load(Ordering::SeqCst), 0);
+}
+
+fn say_hello(_: &mut Context) -> Outputs<String> {
+    Outputs::One("hello".to_string())
+}
+
+fn say_goodbye(_: &mut Context) -> Outputs<String> {
+    Outputs::One("goodbye".to_string())
+}
+
+#[test]
+fn test_reset_lifecycle() {
+    let mut fsm = new_fsm();
+    let sockets = fsm.ctx.sockets.clone();
+    fsm.on_exit("connected", say_goodbye);
+    fsm.on_entry("connecting", say_hello);
+    fsm.on_entry_guard("connecting", open_socket);
+    fsm.send(Msg::Connected);
+    assert_eq!(sockets.load(Ordering::SeqCst), 0);
+
+    // Resetting leaves `connected` and enters `connecting` again
+    let output = fsm.reset(Context { sockets: sockets.clone(), ..Context::default() });
+    assert_eq!(output, vec!["goodbye", "hello"]);
+    assert_eq!(sockets.load(Ordering::SeqCst), 1);
+    fsm.send(Msg::Connected);
+    assert_eq!(sockets.load(Ordering::SeqCst), 0);
+}
+
+#[cfg(feature = "test-util")]
+#[test]
+fn test_set_state() {
+    let clock = MockClock::new();
+    let mut fsm = new_fsm();
+    fsm.set_clock(Arc::new(clock.clone()));
+    let sockets = fsm.ctx.sockets.clone();
+    fsm.on_entry("connecting", say_hello);
+    fsm.on_entry_guard("connecting", open_socket);
+    fsm.set_state_timeout("connecting", Duration::from_secs(5), || Msg::Shutdown);
+    fsm.send(Msg::Connected);
+
+    // Starting mid-scenario enters the state as a transition would
+    let output = fsm.set_state(state_fn!(connecting), Context { pings: 3, ..fsm.ctx.clone() });
+    assert_eq!(output, vec!["hello"]);
+    assert_eq!(sockets.load(Ordering::SeqCst), 1);
+    assert_eq!(fsm.ctx.pings, 3);
+    clock.advance(Duration::from_secs(5));
+    assert_eq!(fsm.poll_timers(), vec!["bye"]);
+    assert_eq!(sockets.load(Ordering::SeqCst), 0);
+}
+
+fn drop_request_zero(_: &mut Context, msg: Msg) -> Option<Msg> {
+    match msg {
+        Msg::Request(0) => None,
+        msg => Some(msg)
+    }
+}
+
+fn dedup(_: &mut Context, output: Outputs<String>) -> Outputs<String> {
+    let mut output = output.into_vec();
+    output.dedup();
+    output.into()
+}
+
+#[test]
+fn test_middleware() {
+    let mut fsm = new_fsm();
+    fsm.add_msg_middleware(drop_request_zero);
+    fsm.add_output_middleware(dedup);
+    for id in [1, 1, 0] {
+        fsm.send(Msg::Request(id));
+    }
+    assert_eq!(fsm.deferred_len(), 3);
+    assert_eq!(fsm.send(Msg::Connected), vec!["response 1"]);
+    assert!(fsm.send(Msg::Request(0)).is_empty());
+}
+
+#[test]
+fn test_init() {
+    let (mut fsm, output) = Fsm::<ConnTypes>::with_init(Context::default(), init);
+    assert_eq!(output, vec!["hello"]);
+    assert_eq!(fsm.get_state().0, "connecting");
+    fsm.send(Msg::Connected);
+    fsm.reset(Context::default());
+    assert_eq!(fsm.get_state().0, "connecting");
+
+    let ctx = Context { sockets: Arc::new(AtomicUsize::new(1)), ..Context::default() };
+    let (fsm, output) = Fsm::<ConnTypes>::with_init(ctx, init);
+    assert!(output.is_empty());
+    assert_eq!(fsm.get_state().0, "connected");
+}
+
+#[test]
+fn test_init_done() {
+    let (fsm, output) = Fsm::<ConnTypes>::with_init(Context::default(), |_| done!());
+    assert!(output.is_empty());
+    assert!(fsm.is_finished());
+}
+
+#[test]
+#[should_panic(expected = "The init function returned previous!(), but there is no previous state")]
+fn test_init_previous() {
+    Fsm::<ConnTypes>::with_init(Context::default(), |_| previous!());
+}
@@ -0,0 +1,63 @@
+//! A turnstile whose types and state registry are derived
+
+#[macro_use]
+extern crate funfsm;
+
+use funfsm::{Fsm, StateFn, FsmTypes, Outputs};
+use funfsm::constraints::Constraints;
+
+#[derive(Debug)]
+pub enum TurnstileMsg {
+    Coin,
+    Push
+}
+
+#[derive(Debug, FsmTypes)]
+#[fsm(context = u32, msg = TurnstileMsg, output = (), states(locked, unlocked))]
+pub struct TurnstileTypes;
+
+pub fn locked(coins: &mut u32, msg: TurnstileMsg) -> (StateFn<TurnstileTypes>, Outputs<()>) {
+    match msg {
+        TurnstileMsg::Coin => {
+            *coins += 1;
+            next!(unlocked)
+        },
+        TurnstileMsg::Push => next!(locked)
+    }
+}
+
+pub fn unlocked(_: &mut u32, msg: TurnstileMsg) -> (StateFn<TurnstileTypes>, Outputs<()>) {
+    match msg {
+        TurnstileMsg::Coin => next!(unlocked),
+        TurnstileMsg::Push => next!(locked)
+    }
+}
+
+#[test]
+fn test_derived_state_enum() {
+    let mut fsm = Fsm::<TurnstileTypes>::new(0, TurnstileState::Locked.state_fn());
+    fsm.send(TurnstileMsg::Coin);
+    let state = TurnstileState::from_name(fsm.get_state().0);
+    assert_eq!(state, Some(TurnstileState::Unlocked));
+    assert_eq!(TurnstileState::from_name("jammed"), None);
+
+    let names: Vec<_> = TurnstileState::ALL.iter().map(|s| s.name()).collect();
+    assert_eq!(names, vec!["locked", "unlocked"]);
+}
+
+#[test]
+fn test_derived_state_registry() {
+    let registry = TurnstileState::registry();
+    assert_eq!(registry.get("unlocked").map(|s| s.0), Some("unlocked"));
+    assert!(registry.get("jammed").is_none());
+}
+
+#[test]
+fn test_derived_state_constraints() {
+    let mut c = Constraints::<TurnstileTypes>::new();
+    state_invariant!(c, TurnstileState::Unlocked, |coins: &u32| *coins > 0);
+    forbid_transition!(c, TurnstileState::Unlocked => TurnstileState::Unlocked);
+    assert!(c.check_state_invariants("unlocked", &0).is_err());
+    assert!(c.check_state_invariants("locked", &0).is_ok());
+    assert!(c.check_forbidden("unlocked", "unlocked").is_err());
+}
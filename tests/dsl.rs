@@ -0,0 +1,62 @@
+//! The cat bowl as a transition table. Unlike the hand-written machine it can't look at the
+//! context, so the cat gets a full bowl every time she meows at an empty one.
+
+#[macro_use]
+extern crate funfsm;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use funfsm::{Outputs, StateFn};
+use funfsm::fsm_check::Checker;
+
+static CLONES: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Default)]
+pub struct Context;
+
+impl Clone for Context {
+    fn clone(&self) -> Context {
+        CLONES.fetch_add(1, Ordering::SeqCst);
+        Context
+    }
+}
+
+impl_display_state_via_debug!(Context);
+
+#[derive(Debug, Clone)]
+pub enum BowlMsg {
+    Meow,
+    Eat(u8)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum StoreReq {
+    Buy(u8)
+}
+
+fsm! {
+    types BowlTypes { context = Context, msg = BowlMsg, output = StoreReq }
+    constraints bowl_constraints;
+    state empty {
+        on BowlMsg::Meow => full emits [StoreReq::Buy(10)];
+    }
+    state full {
+        on BowlMsg::Eat(100) => empty;
+        on BowlMsg::Eat(_) => full;
+    }
+}
+
+#[test]
+fn test_table_machine() {
+    let mut checker = Checker::<BowlTypes>::new(Context, state_fn!(empty), bowl_constraints());
+    checker.require_declared_transitions();
+    assert_eq!(checker.check(BowlMsg::Eat(10)), Ok(Outputs::Empty));
+    assert_eq!(checker.check(BowlMsg::Meow), Ok(Outputs::One(StoreReq::Buy(10))));
+    assert_eq!(checker.check(BowlMsg::Meow), Ok(Outputs::Empty));
+    assert_eq!(checker.check(BowlMsg::Eat(50)), Ok(Outputs::Empty));
+    assert_eq!(checker.fsm.get_state().0, "full");
+    assert_eq!(checker.check(BowlMsg::Eat(100)), Ok(Outputs::Empty));
+    assert_eq!(checker.fsm.get_state().0, "empty");
+
+    // Declared transitions have no checks, so the checker never needs a copy of the context
+    assert_eq!(CLONES.load(Ordering::SeqCst), 0);
+}
@@ -0,0 +1,94 @@
+//! A dialer that retries a few times before giving up. The retry count belongs to the dialing
+//! state, so it starts from zero every time the connection drops and dialing begins again.
+
+extern crate funfsm;
+
+use funfsm::{FsmTypes, Outputs};
+use funfsm::dyn_state::{DynFsm, FnState, Next, State};
+
+#[derive(Debug, Default)]
+pub struct Context {
+    pub connections: u32
+}
+
+#[derive(Debug)]
+pub enum DialMsg {
+    Failed,
+    Connected,
+    Dropped
+}
+
+#[derive(Debug)]
+pub struct DialTypes;
+
+impl FsmTypes for DialTypes {
+    type Context = Context;
+    type Msg = DialMsg;
+    type Output = String;
+}
+
+const MAX_ATTEMPTS: u32 = 3;
+
+#[derive(Default)]
+pub struct Dialing {
+    attempts: u32
+}
+
+impl State<DialTypes> for Dialing {
+    fn name(&self) -> &'static str {
+        "dialing"
+    }
+
+    fn handle(&mut self, ctx: &mut Context, msg: DialMsg) -> (Next<DialTypes>, Outputs<String>) {
+        match msg {
+            DialMsg::Failed => {
+                self.attempts += 1;
+                if self.attempts == MAX_ATTEMPTS {
+                    return (Next::Goto(Box::new(FnState::new("gave_up", gave_up))),
+                            Outputs::One("giving up".to_string()));
+                }
+                (Next::Stay, Outputs::One(format!("retry {}", self.attempts)))
+            },
+            DialMsg::Connected => {
+                ctx.connections += 1;
+                (Next::Goto(online()), Outputs::Empty)
+            },
+            DialMsg::Dropped => (Next::Stay, Outputs::Empty)
+        }
+    }
+}
+
+fn online() -> Box<dyn State<DialTypes>> {
+    let mut drops_seen = 0;
+    Box::new(FnState::new("online", move |_: &mut Context, msg| {
+        match msg {
+            DialMsg::Dropped => {
+                drops_seen += 1;
+                let output = Outputs::One(format!("drop {}", drops_seen));
+                (Next::Goto(Box::new(Dialing::default())), output)
+            },
+            _ => (Next::Stay, Outputs::Empty)
+        }
+    }))
+}
+
+fn gave_up(_: &mut Context, _: DialMsg) -> (Next<DialTypes>, Outputs<String>) {
+    (Next::Stay, Outputs::Empty)
+}
+
+#[test]
+fn test_state_local_data() {
+    let mut fsm = DynFsm::<DialTypes>::new(Context::default(), Box::new(Dialing::default()));
+    assert_eq!(fsm.send(DialMsg::Failed), vec!["retry 1"]);
+    assert_eq!(fsm.send(DialMsg::Failed), vec!["retry 2"]);
+    fsm.send(DialMsg::Connected);
+    assert_eq!(fsm.get_state().0, "online");
+
+    // Re-entering dialing starts with a fresh retry count
+    assert_eq!(fsm.send(DialMsg::Dropped), vec!["drop 1"]);
+    assert_eq!(fsm.send(DialMsg::Failed), vec!["retry 1"]);
+    assert_eq!(fsm.send(DialMsg::Failed), vec!["retry 2"]);
+    assert_eq!(fsm.send(DialMsg::Failed), vec!["giving up"]);
+    assert_eq!(fsm.get_state().0, "gave_up");
+    assert_eq!(fsm.get_state().1.connections, 1);
+}
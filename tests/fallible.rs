@@ -0,0 +1,100 @@
+//! A meter that rejects impossible readings
+
+#[macro_use]
+extern crate funfsm;
+
+use funfsm::{FsmTypes, Outputs};
+use funfsm::fallible::{TryFsm, TryFsmTypes, TryStateFn, SendError};
+use funfsm::fsm::FailurePolicy;
+
+#[derive(Debug, Clone, Default)]
+pub struct Context {
+    pub total: u64
+}
+
+#[derive(Debug, Clone)]
+pub enum MeterMsg {
+    Reading(i64),
+    Reset
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MeterError {
+    Negative(i64)
+}
+
+#[derive(Debug)]
+pub struct MeterTypes;
+
+impl FsmTypes for MeterTypes {
+    type Context = Context;
+    type Msg = MeterMsg;
+    type Output = u64;
+}
+
+impl TryFsmTypes for MeterTypes {
+    type Error = MeterError;
+}
+
+type Next = Result<(TryStateFn<MeterTypes>, Outputs<u64>), MeterError>;
+
+pub fn metering(ctx: &mut Context, msg: MeterMsg) -> Next {
+    match msg {
+        MeterMsg::Reading(n) if n < 0 => Err(MeterError::Negative(n)),
+        MeterMsg::Reading(n) => {
+            ctx.total += n as u64;
+            try_next!(metering, Outputs::One(ctx.total))
+        },
+        MeterMsg::Reset => try_next!(metering)
+    }
+}
+
+pub fn faulted(ctx: &mut Context, msg: MeterMsg) -> Next {
+    match msg {
+        MeterMsg::Reset => {
+            ctx.total = 0;
+            try_next!(metering)
+        },
+        _ => try_next!(faulted)
+    }
+}
+
+fn new_fsm() -> TryFsm<MeterTypes> {
+    TryFsm::new(Context::default(), try_state_fn!(metering))
+}
+
+#[test]
+fn test_surface_error() {
+    let mut fsm = new_fsm();
+    assert_eq!(fsm.send(MeterMsg::Reading(3)), Ok(Outputs::One(3)));
+    assert_eq!(fsm.send(MeterMsg::Reading(-1)), Err(SendError::Failed(MeterError::Negative(-1))));
+    assert_eq!(fsm.send(MeterMsg::Reading(2)), Ok(Outputs::One(5)));
+}
+
+#[test]
+fn test_error_state() {
+    let mut fsm = new_fsm();
+    fsm.set_error_policy(FailurePolicy::ErrorState(try_state_fn!(faulted)));
+    assert!(fsm.send(MeterMsg::Reading(-1)).is_err());
+    assert_eq!(fsm.get_state().0, "faulted");
+    assert_eq!(fsm.send(MeterMsg::Reading(2)), Ok(Outputs::Empty));
+    fsm.send(MeterMsg::Reset).unwrap();
+    assert_eq!(fsm.get_state().0, "metering");
+}
+
+#[test]
+fn test_halt() {
+    let mut fsm = new_fsm();
+    fsm.set_error_policy(FailurePolicy::Halt);
+    assert!(fsm.send(MeterMsg::Reading(-1)).is_err());
+    assert!(fsm.is_halted());
+    assert_eq!(fsm.send(MeterMsg::Reading(2)), Err(SendError::Halted));
+}
+
+#[test]
+#[should_panic(expected = "State metering failed: Negative(-1)")]
+fn test_strict() {
+    let mut fsm = new_fsm();
+    fsm.set_error_policy(FailurePolicy::Strict);
+    let _ = fsm.send(MeterMsg::Reading(-1));
+}
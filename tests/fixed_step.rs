@@ -0,0 +1,66 @@
+//! A walker that moves one unit per tick while it is walking
+
+#[macro_use]
+extern crate funfsm;
+
+use std::time::Duration;
+use funfsm::{Fsm, StateFn, FsmTypes, Outputs};
+use funfsm::fixed_step::FixedStep;
+
+#[derive(Debug, Clone, Default)]
+pub struct Context {
+    pub position: u32
+}
+
+#[derive(Debug, Clone)]
+pub enum WalkerMsg {
+    Tick(Duration),
+    Go,
+    Stop
+}
+
+#[derive(Debug)]
+pub struct WalkerTypes;
+
+impl FsmTypes for WalkerTypes {
+    type Context = Context;
+    type Msg = WalkerMsg;
+    type Output = u32;
+}
+
+pub fn standing(_: &mut Context, msg: WalkerMsg) -> (StateFn<WalkerTypes>, Outputs<u32>) {
+    match msg {
+        WalkerMsg::Go => next!(walking),
+        _ => next!(standing)
+    }
+}
+
+pub fn walking(ctx: &mut Context, msg: WalkerMsg) -> (StateFn<WalkerTypes>, Outputs<u32>) {
+    match msg {
+        WalkerMsg::Tick(_) => {
+            ctx.position += 1;
+            next!(walking, vec![ctx.position])
+        },
+        WalkerMsg::Stop => next!(standing),
+        WalkerMsg::Go => next!(walking)
+    }
+}
+
+#[test]
+fn test_fixed_step() {
+    let fsm = Fsm::<WalkerTypes>::new(Context::default(), state_fn!(standing));
+    let mut driver = FixedStep::new(fsm, Duration::from_millis(10), WalkerMsg::Tick);
+
+    // Ticks while standing don't move the walker
+    assert!(driver.update(Duration::from_millis(25)).is_empty());
+    assert_eq!(driver.leftover(), Duration::from_millis(5));
+
+    // Queued inputs are delivered before the frame's ticks
+    driver.push(WalkerMsg::Go);
+    assert_eq!(driver.update(Duration::from_millis(16)), vec![1, 2]);
+    assert_eq!(driver.leftover(), Duration::from_millis(1));
+
+    driver.push(WalkerMsg::Stop);
+    assert!(driver.update(Duration::from_millis(30)).is_empty());
+    assert_eq!(driver.fsm.get_state().0, "standing");
+}
@@ -0,0 +1,101 @@
+//! A handshake machine that hands off to a session machine once the peer has said hello. Data that
+//! arrives before the handshake completes is deferred, then delivered to the session in order.
+
+#[macro_use]
+extern crate funfsm;
+
+use funfsm::{Fsm, StateFn, FsmTypes, Outputs};
+
+#[derive(Debug)]
+pub enum HandshakeMsg {
+    Hello(String),
+    Data(u32)
+}
+
+#[derive(Debug)]
+pub struct HandshakeTypes;
+
+impl FsmTypes for HandshakeTypes {
+    type Context = Option<String>;
+    type Msg = HandshakeMsg;
+    type Output = &'static str;
+}
+
+type Next = (StateFn<HandshakeTypes>, Outputs<&'static str>);
+
+pub fn greeting(peer: &mut Option<String>, msg: HandshakeMsg) -> Next {
+    if let HandshakeMsg::Hello(name) = msg {
+        *peer = Some(name);
+        return next!(greeted, vec!["hello"]);
+    }
+    next!(greeting)
+}
+
+// Holds on to data until the session takes over
+pub fn greeted(_: &mut Option<String>, _: HandshakeMsg) -> Next {
+    next!(greeted)
+}
+
+#[derive(Debug)]
+pub struct Session {
+    pub peer: String,
+    pub received: u32
+}
+
+#[derive(Debug)]
+pub struct SessionTypes;
+
+impl FsmTypes for SessionTypes {
+    type Context = Session;
+    type Msg = u32;
+    type Output = String;
+}
+
+pub fn established(session: &mut Session, data: u32) -> (StateFn<SessionTypes>, Outputs<String>) {
+    session.received += 1;
+    next!(established, vec![format!("{} sent {}", session.peer, data)])
+}
+
+fn say_done(_: &mut Option<String>) -> Outputs<&'static str> {
+    Outputs::One("handshake done")
+}
+
+fn say_welcome(session: &mut Session) -> Outputs<String> {
+    Outputs::One(format!("welcome {}", session.peer))
+}
+
+fn is_data(msg: &HandshakeMsg) -> bool {
+    matches!(*msg, HandshakeMsg::Data(_))
+}
+
+#[test]
+fn test_hand_off() {
+    let mut handshake = Fsm::builder(None, state_fn!(greeting))
+        .defer("greeting", is_data)
+        .defer("greeted", is_data)
+        .mark_final("greeted")
+        .on_exit("greeted", say_done)
+        .buffer_outputs()
+        .build();
+    handshake.send(HandshakeMsg::Data(1));
+    handshake.send(HandshakeMsg::Data(2));
+    handshake.send(HandshakeMsg::Hello("alice".to_string()));
+    assert!(handshake.is_finished());
+    handshake.send(HandshakeMsg::Data(3));
+
+    let (mut session, last, output) = handshake.hand_off(
+        |peer| {
+            let session = Session { peer: peer.unwrap(), received: 0 };
+            Fsm::builder(session, state_fn!(established))
+                .on_entry("established", say_welcome)
+                .build()
+        },
+        |msg| match msg {
+            HandshakeMsg::Data(n) => Some(n),
+            HandshakeMsg::Hello(_) => None
+        });
+    assert_eq!(last, vec!["hello", "handshake done"]);
+    assert_eq!(output, vec!["welcome alice", "alice sent 1", "alice sent 2", "alice sent 3"]);
+    assert_eq!(session.send(4), vec!["alice sent 4"]);
+    assert_eq!(session.ctx.received, 4);
+}
@@ -0,0 +1,82 @@
+//! An approval workflow that survives a restart by way of a serialized snapshot
+
+#[macro_use]
+extern crate funfsm;
+extern crate serde;
+extern crate serde_json;
+
+use serde::{Serialize, Deserialize};
+use funfsm::{Fsm, StateFn, FsmTypes, Outputs};
+use funfsm::persist::{Snapshot, StateRegistry};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Context {
+    pub approvals: u32
+}
+
+#[derive(Debug)]
+pub enum Msg {
+    Approve,
+    Reject
+}
+
+#[derive(Debug)]
+pub struct WorkflowTypes;
+
+impl FsmTypes for WorkflowTypes {
+    type Context = Context;
+    type Msg = Msg;
+    type Output = ();
+}
+
+const REQUIRED: u32 = 2;
+
+pub fn pending(ctx: &mut Context, msg: Msg) -> (StateFn<WorkflowTypes>, Outputs<()>) {
+    match msg {
+        Msg::Approve => {
+            ctx.approvals += 1;
+            if ctx.approvals == REQUIRED {
+                return next!(approved);
+            }
+            next!(pending)
+        },
+        Msg::Reject => next!(rejected)
+    }
+}
+
+pub fn approved(_: &mut Context, _: Msg) -> (StateFn<WorkflowTypes>, Outputs<()>) {
+    next!(approved)
+}
+
+pub fn rejected(_: &mut Context, _: Msg) -> (StateFn<WorkflowTypes>, Outputs<()>) {
+    next!(rejected)
+}
+
+fn registry() -> StateRegistry<WorkflowTypes> {
+    let mut registry = StateRegistry::new();
+    registry.register(state_fn!(pending));
+    registry.register(state_fn!(approved));
+    registry.register(state_fn!(rejected));
+    registry
+}
+
+#[test]
+fn test_snapshot_and_restore() {
+    let mut fsm = Fsm::<WorkflowTypes>::new(Context::default(), state_fn!(pending));
+    fsm.send(Msg::Approve);
+    let json = serde_json::to_string(&fsm.snapshot()).unwrap();
+    drop(fsm);
+
+    let snapshot: Snapshot<Context> = serde_json::from_str(&json).unwrap();
+    let mut fsm = Fsm::restore(snapshot, &registry()).unwrap();
+    assert_eq!(fsm.get_state().0, "pending");
+    fsm.send(Msg::Approve);
+    assert_eq!(fsm.get_state().0, "approved");
+}
+
+#[test]
+fn test_restore_unknown_state() {
+    let snapshot = Snapshot { state: "escalated".to_string(), ctx: Context::default() };
+    let err = Fsm::restore(snapshot, &registry()).err().unwrap();
+    assert_eq!(err, "Unknown state escalated in snapshot");
+}
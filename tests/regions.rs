@@ -0,0 +1,81 @@
+//! A device with independent power and connectivity states
+
+#[macro_use]
+extern crate funfsm;
+
+use funfsm::{StateFn, FsmTypes, Outputs};
+use funfsm::regions::Regions;
+
+#[derive(Debug, Clone, Default)]
+pub struct Context {
+    pub battery_saver: bool
+}
+
+#[derive(Debug, Clone)]
+pub enum DeviceMsg {
+    PowerSave,
+    PowerFull,
+    LinkUp,
+    LinkDown
+}
+
+#[derive(Debug)]
+pub struct DeviceTypes;
+
+impl FsmTypes for DeviceTypes {
+    type Context = Context;
+    type Msg = DeviceMsg;
+    type Output = &'static str;
+}
+
+type Next = (StateFn<DeviceTypes>, Outputs<&'static str>);
+
+pub fn full_power(ctx: &mut Context, msg: DeviceMsg) -> Next {
+    match msg {
+        DeviceMsg::PowerSave => {
+            ctx.battery_saver = true;
+            next!(low_power, vec!["dim screen"])
+        },
+        _ => next!(full_power)
+    }
+}
+
+pub fn low_power(ctx: &mut Context, msg: DeviceMsg) -> Next {
+    match msg {
+        DeviceMsg::PowerFull => {
+            ctx.battery_saver = false;
+            next!(full_power)
+        },
+        _ => next!(low_power)
+    }
+}
+
+pub fn offline(ctx: &mut Context, msg: DeviceMsg) -> Next {
+    match msg {
+        DeviceMsg::LinkUp if ctx.battery_saver => next!(online, vec!["sync later"]),
+        DeviceMsg::LinkUp => next!(online, vec!["sync now"]),
+        _ => next!(offline)
+    }
+}
+
+pub fn online(_: &mut Context, msg: DeviceMsg) -> Next {
+    match msg {
+        DeviceMsg::LinkDown => next!(offline),
+        _ => next!(online)
+    }
+}
+
+#[test]
+fn test_regions() {
+    let mut device = Regions::<DeviceTypes>::new(Context::default(),
+                                                 vec![state_fn!(full_power), state_fn!(offline)]);
+    assert_eq!(device.get_state().0, vec!["full_power", "offline"]);
+
+    assert_eq!(device.send(DeviceMsg::LinkUp), vec!["sync now"]);
+    assert_eq!(device.send(DeviceMsg::PowerSave), vec!["dim screen"]);
+    assert_eq!(device.get_state().0, vec!["low_power", "online"]);
+
+    // The connectivity region sees the power region's change to the shared context
+    device.send(DeviceMsg::LinkDown);
+    assert_eq!(device.send(DeviceMsg::LinkUp), vec!["sync later"]);
+}
@@ -0,0 +1,61 @@
+//! A coin operated turnstile, tested one transition per row
+
+#[macro_use]
+extern crate funfsm;
+
+use funfsm::{StateFn, FsmTypes, Outputs};
+
+#[derive(Debug)]
+pub enum Msg {
+    Coin,
+    Push
+}
+
+#[derive(Debug)]
+pub struct TurnstileTypes;
+
+impl FsmTypes for TurnstileTypes {
+    // The number of coins collected
+    type Context = u32;
+    type Msg = Msg;
+    type Output = &'static str;
+}
+
+pub fn locked(coins: &mut u32, msg: Msg) -> (StateFn<TurnstileTypes>, Outputs<&'static str>) {
+    match msg {
+        Msg::Coin => {
+            *coins += 1;
+            next!(unlocked, vec!["unlock"])
+        },
+        Msg::Push => next!(locked)
+    }
+}
+
+pub fn unlocked(coins: &mut u32, msg: Msg) -> (StateFn<TurnstileTypes>, Outputs<&'static str>) {
+    match msg {
+        Msg::Coin => {
+            *coins += 1;
+            next!(unlocked, vec!["refund"])
+        },
+        Msg::Push => next!(locked, vec!["lock"])
+    }
+}
+
+transition_tests! {
+    types TurnstileTypes;
+    coverage turnstile_coverage;
+    coin_unlocks: locked(0), Msg::Coin => unlocked, |coins: &u32| *coins == 1, ["unlock"];
+    push_while_locked: locked(0), Msg::Push => locked, |coins: &u32| *coins == 0, [];
+    push_locks: unlocked(1), Msg::Push => locked, |coins: &u32| *coins == 1, ["lock"];
+}
+
+#[test]
+fn test_coverage() {
+    let counts = turnstile_coverage();
+    assert_eq!(counts.get("locked", "unlocked"), 1);
+    assert_eq!(counts.get("unlocked", "locked"), 1);
+    assert_eq!(counts.get("locked", "locked"), 1);
+
+    // No row puts a coin in an unlocked turnstile
+    assert_eq!(counts.get("unlocked", "unlocked"), 0);
+}